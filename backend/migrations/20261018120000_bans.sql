@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS bans
+(
+    id         SERIAL PRIMARY KEY                                 NOT NULL,
+    user_id    BIGINT                                             NOT NULL,
+    reason     TEXT,
+    issuer     TEXT                                               NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE,
+    revoked_at TIMESTAMP WITH TIME ZONE,
+    revoked_by TEXT,
+    FOREIGN KEY (user_id) REFERENCES users (discord_id)
+);
+
+CREATE INDEX IF NOT EXISTS bans_user_id_idx ON bans (user_id);
+
+INSERT INTO bans (user_id, issuer)
+SELECT discord_id, 'legacy'
+FROM users
+WHERE banned = TRUE
+  AND NOT EXISTS (SELECT 1 FROM bans WHERE bans.user_id = users.discord_id);
\ No newline at end of file
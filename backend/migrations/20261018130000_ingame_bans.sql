@@ -0,0 +1,2 @@
+ALTER TABLE bans
+    ADD IF NOT EXISTS ingame BOOLEAN DEFAULT FALSE NOT NULL;
\ No newline at end of file
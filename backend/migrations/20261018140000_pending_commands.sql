@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS pending_commands
+(
+    id              SERIAL PRIMARY KEY                                 NOT NULL,
+    username        TEXT                                               NOT NULL,
+    command         TEXT                                               NOT NULL,
+    status          TEXT                     DEFAULT 'pending'         NOT NULL,
+    attempts        INTEGER                  DEFAULT 1                 NOT NULL,
+    last_error      TEXT,
+    created_at      TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    next_attempt_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    completed_at    TIMESTAMP WITH TIME ZONE,
+    CHECK (status IN ('pending', 'completed', 'superseded', 'abandoned'))
+);
+
+CREATE INDEX IF NOT EXISTS pending_commands_status_idx ON pending_commands (status, next_attempt_at);
\ No newline at end of file
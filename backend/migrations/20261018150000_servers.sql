@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS servers
+(
+    id               SERIAL PRIMARY KEY                                 NOT NULL,
+    name             TEXT                                               NOT NULL UNIQUE,
+    pterodactyl_id   TEXT                                               NOT NULL,
+    whitelist_policy TEXT                     DEFAULT 'all'             NOT NULL,
+    enabled          BOOLEAN                  DEFAULT TRUE              NOT NULL,
+    created_at       TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    CHECK (whitelist_policy IN ('all', 'admins', 'none'))
+);
+
+ALTER TABLE pending_commands
+    ADD IF NOT EXISTS server_id INTEGER REFERENCES servers (id) ON DELETE CASCADE;
\ No newline at end of file
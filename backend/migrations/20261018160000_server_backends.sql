@@ -0,0 +1,20 @@
+ALTER TABLE servers
+    ADD IF NOT EXISTS backend TEXT DEFAULT 'pterodactyl' NOT NULL,
+    ADD IF NOT EXISTS rcon_address TEXT,
+    ADD IF NOT EXISTS rcon_password TEXT,
+    ALTER pterodactyl_id DROP NOT NULL;
+
+DO
+$$
+    BEGIN
+        IF NOT EXISTS (SELECT 1
+                       FROM pg_constraint
+                       WHERE conname = 'server_backend_config') THEN
+            ALTER TABLE servers
+                ADD CONSTRAINT server_backend_config CHECK (
+                    (backend = 'pterodactyl' AND pterodactyl_id IS NOT NULL) OR
+                    (backend = 'rcon' AND rcon_address IS NOT NULL AND rcon_password IS NOT NULL)
+                );
+        END IF;
+    END
+$$;
\ No newline at end of file
@@ -0,0 +1,2 @@
+ALTER TABLE servers
+    ADD IF NOT EXISTS address TEXT;
\ No newline at end of file
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS minecraft_profiles
+(
+    uuid       UUID PRIMARY KEY                                   NOT NULL,
+    name       TEXT                                               NOT NULL,
+    properties JSONB,
+    fetched_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS minecraft_profiles_name_idx ON minecraft_profiles (LOWER(name));
+CREATE INDEX IF NOT EXISTS minecraft_profiles_fetched_at_idx ON minecraft_profiles (fetched_at);
\ No newline at end of file
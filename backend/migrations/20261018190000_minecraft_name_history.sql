@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS minecraft_name_history
+(
+    id             SERIAL PRIMARY KEY                                 NOT NULL,
+    minecraft_uuid UUID                                               NOT NULL,
+    name           TEXT                                               NOT NULL,
+    first_seen_at  TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    last_seen_at   TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS minecraft_name_history_uuid_idx ON minecraft_name_history (minecraft_uuid, last_seen_at);
+CREATE INDEX IF NOT EXISTS minecraft_name_history_name_idx ON minecraft_name_history (LOWER(name));
\ No newline at end of file
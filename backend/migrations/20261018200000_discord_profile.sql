@@ -0,0 +1,5 @@
+ALTER TABLE users
+    ADD IF NOT EXISTS discord_global_name TEXT;
+
+ALTER TABLE users
+    ADD IF NOT EXISTS discord_avatar TEXT;
\ No newline at end of file
@@ -0,0 +1,5 @@
+ALTER TABLE users
+    ADD IF NOT EXISTS eligible BOOLEAN DEFAULT TRUE NOT NULL;
+
+ALTER TABLE users
+    ADD IF NOT EXISTS eligibility_checked_at TIMESTAMP WITH TIME ZONE;
\ No newline at end of file
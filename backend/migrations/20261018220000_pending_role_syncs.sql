@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS pending_role_syncs
+(
+    discord_id      BIGINT PRIMARY KEY                                 NOT NULL,
+    attempts        INTEGER                  DEFAULT 1                 NOT NULL,
+    last_error      TEXT,
+    created_at      TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    next_attempt_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    FOREIGN KEY (discord_id) REFERENCES users (discord_id) ON DELETE CASCADE
+);
\ No newline at end of file
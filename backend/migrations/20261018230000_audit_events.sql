@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS audit_events
+(
+    id                BIGSERIAL PRIMARY KEY                              NOT NULL,
+    created_at        TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    actor             TEXT                                               NOT NULL,
+    target_discord_id BIGINT,
+    action            TEXT                                               NOT NULL,
+    before            JSONB,
+    after             JSONB,
+    ip                TEXT
+);
+
+CREATE INDEX IF NOT EXISTS audit_events_target_idx ON audit_events (target_discord_id, created_at);
+CREATE INDEX IF NOT EXISTS audit_events_action_idx ON audit_events (action, created_at);
\ No newline at end of file
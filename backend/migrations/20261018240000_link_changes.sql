@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS minecraft_link_changes
+(
+    id             BIGSERIAL PRIMARY KEY                              NOT NULL,
+    discord_id     BIGINT                                             NOT NULL,
+    minecraft_uuid UUID                                               NOT NULL,
+    changed_at     TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    FOREIGN KEY (discord_id) REFERENCES users (discord_id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS minecraft_link_changes_discord_id ON minecraft_link_changes (discord_id, changed_at);
+
+-- Changes made before an admin reset don't count towards the cooldown or the monthly cap.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS link_limit_reset_at TIMESTAMP WITH TIME ZONE;
\ No newline at end of file
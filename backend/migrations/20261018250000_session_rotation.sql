@@ -0,0 +1,6 @@
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL;
+-- Set when the session was swapped for a new one during a token refresh.
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS replaced_by UUID;
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS rotated_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX IF NOT EXISTS sessions_session_id ON sessions (session_id);
\ No newline at end of file
@@ -0,0 +1,6 @@
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS last_seen_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS user_agent TEXT;
+-- Only the network part of the address is kept, see `session_manager::approximate_ip`.
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS ip TEXT;
+
+CREATE INDEX IF NOT EXISTS sessions_user_id ON sessions (user_id);
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Space separated OAuth scopes the session's token was granted, NULL for sessions from before this was stored.
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS scope TEXT;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- When the linked account's name was last resolved, also set when the account no longer exists.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS name_checked_at TIMESTAMP WITH TIME ZONE;
\ No newline at end of file
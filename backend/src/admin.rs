@@ -0,0 +1,354 @@
+use chrono::serde::{ts_seconds, ts_seconds_option};
+use chrono::{DateTime, Utc};
+use rocket::form::Form;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{query, query_as};
+use std::net::IpAddr;
+use uuid::Uuid;
+
+use crate::app::App;
+use crate::audit::{self, Actor, AuditAction, AuditEvent, AuditRecord};
+use crate::command_queue::{self, PendingCommand};
+use crate::reconcile::{self, ReconcileReport};
+use crate::roles;
+use crate::servers::{self, MinecraftServer, ServerData};
+use crate::bans::{self, Ban, BanOptions, BanOutcome, UnbanOutcome};
+use crate::cache::CacheStats;
+use crate::errors::ApiError;
+use crate::link_limits;
+use crate::session_manager::{self, CleanupReport, SessionCounts};
+use crate::name_history::{self, NameHistoryEntry};
+use crate::{fetch_minecraft_profile, link_minecraft_account, minecraft, AdminSession, Whitelist};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Deserialize)]
+pub struct AdminBanData {
+    pub reason: Option<String>,
+    #[serde(default, with = "ts_seconds_option")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub kick: bool,
+    #[serde(default)]
+    pub ingame: bool,
+}
+
+#[derive(Serialize)]
+pub struct AdminUser {
+    pub discord_id: i64,
+    pub discord_username: String,
+    pub discord_global_name: Option<String>,
+    pub discord_avatar: Option<String>,
+    pub minecraft_uuid: Option<Uuid>,
+    #[serde(with = "ts_seconds")]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "ts_seconds")]
+    pub last_updated: DateTime<Utc>,
+    pub is_admin: bool,
+    pub banned: bool,
+}
+
+async fn fetch_user(app: &State<App>, discord_id: i64) -> Result<AdminUser, ApiError> {
+    query_as!(AdminUser, "SELECT discord_id, discord_username, discord_global_name, discord_avatar, minecraft_uuid, created_at, last_updated, is_admin, banned
+                          FROM users WHERE discord_id = $1", discord_id)
+        .fetch_optional(&app.db)
+        .await?
+        .ok_or(ApiError::NotFound)
+}
+
+/// Lists users, optionally filtered by a partial Discord username / display name or an exact Discord ID / Minecraft UUID /
+/// current or past Minecraft name.
+#[get("/admin/users?<search>&<limit>&<offset>")]
+pub async fn admin_list_users(app: &State<App>, _admin: AdminSession, search: Option<&str>, limit: Option<i64>, offset: Option<i64>) -> Result<Json<Vec<AdminUser>>, ApiError> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let offset = offset.unwrap_or(0).max(0);
+
+    let users = query_as!(AdminUser, "SELECT discord_id, discord_username, discord_global_name, discord_avatar, minecraft_uuid, created_at, last_updated, is_admin, banned
+                                      FROM users
+                                      WHERE $1::TEXT IS NULL
+                                         OR discord_username ILIKE '%' || $1 || '%'
+                                         OR discord_global_name ILIKE '%' || $1 || '%'
+                                         OR discord_id::TEXT = $1
+                                         OR REPLACE(minecraft_uuid::TEXT, '-', '') = REPLACE(LOWER($1), '-', '')
+                                         OR minecraft_uuid IN (SELECT minecraft_uuid FROM minecraft_name_history WHERE LOWER(name) = LOWER($1))
+                                      ORDER BY created_at DESC
+                                      LIMIT $2 OFFSET $3", search, limit, offset)
+        .fetch_all(&app.db)
+        .await?;
+
+    Ok(Json(users))
+}
+
+#[get("/admin/users/<discord_id>")]
+pub async fn admin_get_user(app: &State<App>, _admin: AdminSession, discord_id: i64) -> Result<Json<AdminUser>, ApiError> {
+    Ok(Json(fetch_user(app, discord_id).await?))
+}
+
+#[get("/admin/users/<discord_id>/bans")]
+pub async fn admin_get_user_bans(app: &State<App>, _admin: AdminSession, discord_id: i64) -> Result<Json<Vec<Ban>>, ApiError> {
+    fetch_user(app, discord_id).await?;
+
+    Ok(Json(bans::get_bans(app, discord_id).await?))
+}
+
+/// Names the user's linked Minecraft account has gone by, most recent first.
+#[get("/admin/users/<discord_id>/names")]
+pub async fn admin_get_user_names(app: &State<App>, _admin: AdminSession, discord_id: i64) -> Result<Json<Vec<NameHistoryEntry>>, ApiError> {
+    let user = fetch_user(app, discord_id).await?;
+
+    match user.minecraft_uuid {
+        Some(uuid) => Ok(Json(name_history::get_history(app, uuid).await?)),
+        None => Ok(Json(Vec::new())),
+    }
+}
+
+#[post("/admin/users/<discord_id>/ban", data = "<ban_data>")]
+pub async fn admin_ban_user(app: &State<App>, admin: AdminSession, ip: Option<IpAddr>, discord_id: i64, ban_data: Json<AdminBanData>) -> Result<Json<BanOutcome>, ApiError> {
+    fetch_user(app, discord_id).await?;
+
+    let ban_data = ban_data.into_inner();
+    let actor = Actor::user(admin.0.user.discord_id, ip);
+    let options = BanOptions {
+        reason: ban_data.reason,
+        expires_at: ban_data.expires_at,
+        kick: ban_data.kick,
+        ingame: ban_data.ingame,
+    };
+
+    Ok(Json(bans::ban_user(app, discord_id, &actor, options).await?))
+}
+
+#[post("/admin/users/<discord_id>/unban")]
+pub async fn admin_unban_user(app: &State<App>, admin: AdminSession, ip: Option<IpAddr>, discord_id: i64) -> Result<Json<UnbanOutcome>, ApiError> {
+    fetch_user(app, discord_id).await?;
+
+    let actor = Actor::user(admin.0.user.discord_id, ip);
+
+    Ok(Json(bans::unban_user(app, discord_id, &actor).await?))
+}
+
+/// Removes the user's linked Minecraft account and takes it off the server whitelist.
+#[post("/admin/users/<discord_id>/unlink")]
+pub async fn admin_unlink_user(app: &State<App>, admin: AdminSession, ip: Option<IpAddr>, discord_id: i64) -> Result<Json<AdminUser>, ApiError> {
+    let user = fetch_user(app, discord_id).await?;
+
+    if let Some(uuid) = user.minecraft_uuid {
+        // Without the current name the account can't be taken off the whitelist, so nothing is changed then.
+        // Deleted accounts have no name left to remove and are unlinked right away.
+        let name = match fetch_minecraft_profile(app, &uuid.to_string()).await {
+            Ok(profile) => Some(profile.minecraft_username),
+            Err(ApiError::NotFound) => None,
+            Err(err) => return Err(err),
+        };
+        let event = AuditEvent::new(AuditAction::Unlink, &Actor::user(admin.0.user.discord_id, ip))
+            .user(discord_id)
+            .minecraft_name(name.clone())
+            .change(name.clone().or_else(|| Some(uuid.to_string())), None)
+            .snapshot(Some(json!({ "minecraft_uuid": uuid, "minecraft_name": name })), None);
+
+        let mut tx = app.db.begin().await?;
+
+        query!("UPDATE users SET minecraft_uuid = NULL WHERE discord_id = $1", discord_id)
+            .execute(&mut *tx)
+            .await?;
+
+        audit::record(&mut tx, &event).await?;
+        tx.commit().await?;
+
+        if let Some(name) = &name {
+            minecraft::minecraft_whitelist_remove(app, name).await;
+        }
+
+        roles::sync_user(app, discord_id).await;
+        audit::notify(app, &event);
+    }
+
+    Ok(Json(fetch_user(app, discord_id).await?))
+}
+
+/// Links and whitelists a Minecraft account for the user, bypassing the ban check.
+#[post("/admin/users/<discord_id>/whitelist", data = "<whitelist_data>")]
+pub async fn admin_whitelist_user(app: &State<App>, admin: AdminSession, ip: Option<IpAddr>, discord_id: i64, whitelist_data: Form<Whitelist>) -> Result<Json<AdminUser>, ApiError> {
+    let user = fetch_user(app, discord_id).await?;
+
+    let actor = Actor::user(admin.0.user.discord_id, ip);
+    link_minecraft_account(app, &actor, discord_id, user.minecraft_uuid, &whitelist_data.username, false).await?;
+
+    Ok(Json(fetch_user(app, discord_id).await?))
+}
+
+/// Lets the user change their linked Minecraft account again right away, ignoring the cooldown and monthly cap.
+#[post("/admin/users/<discord_id>/link_limits/reset")]
+pub async fn admin_reset_link_limits(app: &State<App>, admin: AdminSession, ip: Option<IpAddr>, discord_id: i64) -> Result<Json<AdminUser>, ApiError> {
+    let event = AuditEvent::new(AuditAction::LinkLimitReset, &Actor::user(admin.0.user.discord_id, ip))
+        .user(discord_id);
+
+    let mut tx = app.db.begin().await?;
+    link_limits::reset(&mut tx, discord_id).await?;
+    audit::record(&mut tx, &event).await?;
+    tx.commit().await?;
+    audit::notify(app, &event);
+
+    Ok(Json(fetch_user(app, discord_id).await?))
+}
+
+/// Lists console commands that failed and are still waiting to be retried or were given up on.
+#[get("/admin/commands/pending")]
+pub async fn admin_list_pending_commands(app: &State<App>, _admin: AdminSession) -> Result<Json<Vec<PendingCommand>>, ApiError> {
+    Ok(Json(command_queue::get_stuck_commands(app).await?))
+}
+
+#[post("/admin/commands/<id>/retry")]
+pub async fn admin_retry_pending_command(app: &State<App>, admin: AdminSession, ip: Option<IpAddr>, id: i32) -> Result<Json<PendingCommand>, ApiError> {
+    let mut tx = app.db.begin().await?;
+
+    let command = command_queue::reschedule(&mut tx, id).await?;
+    let event = AuditEvent::new(AuditAction::CommandRetry, &Actor::user(admin.0.user.discord_id, ip))
+        .minecraft_name(Some(command.username.clone()))
+        .details(format!("#{}: {}", command.id, command.command))
+        .snapshot(None, Some(serde_json::to_value(&command)?));
+
+    audit::record(&mut tx, &event).await?;
+    tx.commit().await?;
+    audit::notify(app, &event);
+
+    Ok(Json(command))
+}
+
+#[delete("/admin/commands/<id>")]
+pub async fn admin_discard_pending_command(app: &State<App>, admin: AdminSession, ip: Option<IpAddr>, id: i32) -> Result<Json<PendingCommand>, ApiError> {
+    let mut tx = app.db.begin().await?;
+
+    let command = command_queue::discard(&mut tx, id).await?;
+    let event = AuditEvent::new(AuditAction::CommandDiscard, &Actor::user(admin.0.user.discord_id, ip))
+        .minecraft_name(Some(command.username.clone()))
+        .details(format!("#{}: {}", command.id, command.command))
+        .snapshot(None, Some(serde_json::to_value(&command)?));
+
+    audit::record(&mut tx, &event).await?;
+    tx.commit().await?;
+    audit::notify(app, &event);
+
+    Ok(Json(command))
+}
+
+/// Reports differences between the linked accounts and the server whitelist without changing anything.
+#[get("/admin/whitelist/drift")]
+pub async fn admin_whitelist_drift(app: &State<App>, _admin: AdminSession) -> Result<Json<Vec<ReconcileReport>>, ApiError> {
+    Ok(Json(reconcile::reconcile(app, false).await?))
+}
+
+#[post("/admin/whitelist/reconcile")]
+pub async fn admin_whitelist_reconcile(app: &State<App>, admin: AdminSession, ip: Option<IpAddr>) -> Result<Json<Vec<ReconcileReport>>, ApiError> {
+    let reports = reconcile::reconcile(app, true).await?;
+
+    let summary = reports.iter()
+        .map(|report| match &report.error {
+            Some(error) => format!("{}: failed, {}", report.server, error),
+            None => format!("{}: {} added, {} removed, {} unresolved", report.server, report.missing.len(), report.extra.len(), report.unresolved.len()),
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    let event = AuditEvent::new(AuditAction::WhitelistReconcile, &Actor::user(admin.0.user.discord_id, ip))
+        .details(if summary.is_empty() { "No servers to reconcile".to_string() } else { summary })
+        .snapshot(None, Some(serde_json::to_value(&reports)?));
+
+    // The changes happened on the servers, so there's no transaction to attach the event to.
+    audit::record(&mut *app.db.acquire().await?, &event).await?;
+    audit::notify(app, &event);
+
+    Ok(Json(reports))
+}
+
+#[get("/admin/servers")]
+pub async fn admin_list_servers(app: &State<App>, _admin: AdminSession) -> Result<Json<Vec<MinecraftServer>>, ApiError> {
+    Ok(Json(servers::get_servers(app).await?))
+}
+
+#[get("/admin/servers/<id>")]
+pub async fn admin_get_server(app: &State<App>, _admin: AdminSession, id: i32) -> Result<Json<MinecraftServer>, ApiError> {
+    Ok(Json(servers::get_server(app, id).await?))
+}
+
+#[post("/admin/servers", data = "<server_data>")]
+pub async fn admin_create_server(app: &State<App>, admin: AdminSession, ip: Option<IpAddr>, server_data: Json<ServerData>) -> Result<Json<MinecraftServer>, ApiError> {
+    let mut tx = app.db.begin().await?;
+
+    let server = servers::create_server(&mut tx, server_data.into_inner()).await?;
+    let event = AuditEvent::new(AuditAction::ServerCreate, &Actor::user(admin.0.user.discord_id, ip))
+        .change(None, Some(server.describe()))
+        .snapshot(None, Some(serde_json::to_value(&server)?));
+
+    audit::record(&mut tx, &event).await?;
+    tx.commit().await?;
+    audit::notify(app, &event);
+
+    Ok(Json(server))
+}
+
+#[put("/admin/servers/<id>", data = "<server_data>")]
+pub async fn admin_update_server(app: &State<App>, admin: AdminSession, ip: Option<IpAddr>, id: i32, server_data: Json<ServerData>) -> Result<Json<MinecraftServer>, ApiError> {
+    let previous = servers::get_server(app, id).await?;
+    let mut tx = app.db.begin().await?;
+
+    let server = servers::update_server(&mut tx, id, server_data.into_inner()).await?;
+    let event = AuditEvent::new(AuditAction::ServerUpdate, &Actor::user(admin.0.user.discord_id, ip))
+        .change(Some(previous.describe()), Some(server.describe()))
+        .snapshot(Some(serde_json::to_value(&previous)?), Some(serde_json::to_value(&server)?));
+
+    audit::record(&mut tx, &event).await?;
+    tx.commit().await?;
+    audit::notify(app, &event);
+
+    Ok(Json(server))
+}
+
+#[delete("/admin/servers/<id>")]
+pub async fn admin_delete_server(app: &State<App>, admin: AdminSession, ip: Option<IpAddr>, id: i32) -> Result<Json<MinecraftServer>, ApiError> {
+    let mut tx = app.db.begin().await?;
+
+    let server = servers::delete_server(&mut tx, id).await?;
+    let event = AuditEvent::new(AuditAction::ServerDelete, &Actor::user(admin.0.user.discord_id, ip))
+        .change(Some(server.describe()), None)
+        .snapshot(Some(serde_json::to_value(&server)?), None);
+
+    audit::record(&mut tx, &event).await?;
+    tx.commit().await?;
+    audit::notify(app, &event);
+
+    Ok(Json(server))
+}
+
+/// Lists audit events, newest first, optionally filtered by the affected user and the action.
+#[get("/admin/audit?<discord_id>&<action>&<limit>&<offset>")]
+pub async fn admin_list_audit_events(app: &State<App>, _admin: AdminSession, discord_id: Option<i64>, action: Option<&str>, limit: Option<i64>, offset: Option<i64>) -> Result<Json<Vec<AuditRecord>>, ApiError> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let offset = offset.unwrap_or(0).max(0);
+
+    Ok(Json(audit::get_events(app, discord_id, action, limit, offset).await?))
+}
+
+#[derive(Serialize)]
+pub struct Metrics {
+    pub sessions: SessionCounts,
+    pub session_cleanup: CleanupReport,
+    pub caches: Vec<CacheStats>,
+}
+
+#[get("/admin/metrics")]
+pub async fn admin_metrics(app: &State<App>, _admin: AdminSession) -> Result<Json<Metrics>, ApiError> {
+    Ok(Json(Metrics {
+        sessions: session_manager::count_sessions(app).await?,
+        session_cleanup: app.session_cleanup.report(),
+        caches: app.cache.stats(),
+    }))
+}
+
+#[get("/admin/cache/stats")]
+pub async fn admin_cache_stats(app: &State<App>, _admin: AdminSession) -> Json<Vec<CacheStats>> {
+    Json(app.cache.stats())
+}
\ No newline at end of file
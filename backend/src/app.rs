@@ -1,16 +1,79 @@
-use reqwest;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
 use sqlx::postgres::PgPoolOptions;
 use sqlx::{Pool, Postgres};
-use std::collections::HashMap;
 use std::env;
-use std::sync::{Arc, RwLock};
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
 
+use crate::cache::{CacheStats, TypedCache};
+use crate::guild::GuildRules;
+use crate::link_limits::LinkLimits;
+use crate::rate_limit::RateLimiter;
+use crate::session_manager::CleanupStats;
+use crate::roles::RoleSync;
+use crate::status::ServerStatus;
+use crate::{DiscordUserData, MinecraftUserData, MinecraftUsernameToUuid};
+
+/// Lookups that found nothing are cached for this long, so repeated typos don't hit Mojang or Discord.
+const NEGATIVE_TTL: Duration = Duration::from_secs(300);
+
+/// The lookup caches, one per kind of value. Public profiles are shared between all users; a `None`
+/// entry records that the profile doesn't exist.
+pub struct Caches {
+    /// Keyed by the lowercased username, Minecraft names are case-insensitive.
+    pub username_to_uuid_minecraft: TypedCache<String, Option<MinecraftUsernameToUuid>>,
+    pub id_to_username_minecraft: TypedCache<Uuid, Option<MinecraftUserData>>,
+    pub id_to_username_discord: TypedCache<i64, Option<DiscordUserData>>,
+    pub server_status: TypedCache<Option<String>, ServerStatus>,
+}
+
+impl Caches {
+    fn new() -> Self {
+        Self {
+            username_to_uuid_minecraft: TypedCache::new("username_to_uuid_minecraft", Duration::from_secs(3600), 10_000)
+                .with_negative_ttl(NEGATIVE_TTL),
+            id_to_username_minecraft: TypedCache::new("id_to_username_minecraft", Duration::from_secs(3600), 10_000)
+                .with_negative_ttl(NEGATIVE_TTL),
+            id_to_username_discord: TypedCache::new("id_to_username_discord", Duration::from_secs(3600), 10_000)
+                .with_negative_ttl(NEGATIVE_TTL),
+            server_status: TypedCache::new("server_status", Duration::from_secs(30), 16),
+        }
+    }
+
+    pub fn stats(&self) -> Vec<CacheStats> {
+        vec![
+            self.username_to_uuid_minecraft.stats(),
+            self.id_to_username_minecraft.stats(),
+            self.id_to_username_discord.stats(),
+            self.server_status.stats(),
+        ]
+    }
+}
+
+#[derive(Clone)]
 pub struct App {
     pub https: reqwest::Client,
     pub db: Pool<Postgres>,
-    pub pterodactyl: pterodactyl_api::client::Client,
-    pub cache: Arc<RwLock<HashMap<(&'static str, u64), (String, Instant)>>>,
+    /// OAuth client credentials, needed to refresh and revoke Discord tokens.
+    pub discord_client_id: String,
+    pub discord_client_secret: String,
+    /// Where users end up after logging in.
+    pub base_url: String,
+    /// Only set when a Pterodactyl panel is configured, RCON-only setups go without one.
+    pub pterodactyl: Option<Arc<pterodactyl_api::client::Client>>,
+    /// Authenticated as the Discord bot, only set when `DISCORD_BOT_TOKEN` is configured.
+    pub discord_bot: Option<reqwest::Client>,
+    /// Whitelist eligibility rules, `None` lets every Discord user link an account.
+    pub guild: Option<GuildRules>,
+    /// Linked roles to keep in sync, needs `discord_bot` as well.
+    pub role_sync: Option<RoleSync>,
+    /// Discord webhook that moderation and whitelist events get posted to.
+    pub audit_webhook: Option<String>,
+    pub cache: Arc<Caches>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub link_limits: LinkLimits,
+    pub session_cleanup: Arc<CleanupStats>,
 }
 
 impl App {
@@ -22,12 +85,40 @@ impl App {
                 .connect(&env::var("DATABASE_URL").expect("Missing Required Env Var DATABASE_URL"))
                 .await.expect("Unknown error occurred while connecting to DB"),
 
-            pterodactyl: pterodactyl_api::client::ClientBuilder::new(
-                env::var("PTERODACTYL_URL").expect("Missing Required Env Var PTERODACTYL_URL"),
-                env::var("PTERODACTYL_APIKEY").expect("Missing Required Env Var PTERODACTYL_APIKEY"),
-            ).build(),
+            discord_client_id: env::var("DISCORD_CLIENT_ID").expect("Missing Required Env Var DISCORD_CLIENT_ID"),
+
+            discord_client_secret: env::var("DISCORD_CLIENT_SECRET").expect("Missing Required Env Var DISCORD_CLIENT_SECRET"),
+
+            base_url: env::var("BASE_URL").expect("Missing Required Env Var BASE_URL"),
+
+            pterodactyl: match (env::var("PTERODACTYL_URL"), env::var("PTERODACTYL_APIKEY")) {
+                (Ok(url), Ok(api_key)) => Some(Arc::new(pterodactyl_api::client::ClientBuilder::new(url, api_key).build())),
+                _ => None,
+            },
+
+            discord_bot: env::var("DISCORD_BOT_TOKEN").ok().map(|token| {
+                let mut headers = HeaderMap::new();
+                headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bot {}", token)).expect("Invalid DISCORD_BOT_TOKEN"));
+
+                reqwest::Client::builder()
+                    .default_headers(headers)
+                    .build()
+                    .expect("Unknown error occurred while building the Discord bot client")
+            }),
+
+            guild: GuildRules::from_env(),
+
+            role_sync: RoleSync::from_env(),
+
+            audit_webhook: env::var("DISCORD_AUDIT_WEBHOOK_URL").ok(),
+
+            cache: Arc::new(Caches::new()),
+
+            rate_limiter: Arc::new(RateLimiter::from_env()),
+
+            link_limits: LinkLimits::from_env(),
 
-            cache: Arc::new(RwLock::new(HashMap::new())),
+            session_cleanup: Arc::new(CleanupStats::default()),
         }
     }
 }
\ No newline at end of file
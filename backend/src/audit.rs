@@ -0,0 +1,340 @@
+use chrono::serde::ts_seconds;
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::{query, query_as, PgConnection};
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+
+use crate::app::App;
+use crate::errors::ApiError;
+
+/// Discord rejects the whole embed if a field value is longer than this.
+const MAX_FIELD_LENGTH: usize = 1024;
+
+#[derive(Clone, Copy)]
+pub enum AuditAction {
+    Link,
+    Relink,
+    Unlink,
+    Ban,
+    Unban,
+    CommandRetry,
+    CommandDiscard,
+    WhitelistReconcile,
+    ServerCreate,
+    ServerUpdate,
+    ServerDelete,
+    Logout,
+    LinkLimitReset,
+}
+
+impl AuditAction {
+    /// The name stored in `audit_events.action`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Link => "link",
+            Self::Relink => "relink",
+            Self::Unlink => "unlink",
+            Self::Ban => "ban",
+            Self::Unban => "unban",
+            Self::CommandRetry => "command_retry",
+            Self::CommandDiscard => "command_discard",
+            Self::WhitelistReconcile => "whitelist_reconcile",
+            Self::ServerCreate => "server_create",
+            Self::ServerUpdate => "server_update",
+            Self::ServerDelete => "server_delete",
+            Self::Logout => "logout",
+            Self::LinkLimitReset => "link_limit_reset",
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Self::Link => "Minecraft account linked",
+            Self::Relink => "Minecraft account changed",
+            Self::Unlink => "Minecraft account unlinked",
+            Self::Ban => "User banned",
+            Self::Unban => "User unbanned",
+            Self::CommandRetry => "Queued command rescheduled",
+            Self::CommandDiscard => "Queued command discarded",
+            Self::WhitelistReconcile => "Whitelist reconciled",
+            Self::ServerCreate => "Server added",
+            Self::ServerUpdate => "Server updated",
+            Self::ServerDelete => "Server removed",
+            Self::Logout => "Logged out",
+            Self::LinkLimitReset => "Account change limits reset",
+        }
+    }
+
+    fn color(self) -> u32 {
+        match self {
+            Self::Link | Self::Unban | Self::ServerCreate => 0x57F287,
+            Self::Relink | Self::ServerUpdate | Self::WhitelistReconcile | Self::CommandRetry | Self::LinkLimitReset => 0x5865F2,
+            Self::Unlink | Self::CommandDiscard | Self::ServerDelete | Self::Logout => 0xFEE75C,
+            Self::Ban => 0xED4245,
+        }
+    }
+}
+
+/// Whoever made a change.
+#[derive(Clone)]
+pub struct Actor {
+    /// Discord ID of the user, or a label such as "api" or "system".
+    pub id: String,
+    pub ip: Option<IpAddr>,
+}
+
+impl Actor {
+    pub fn new(id: impl Into<String>, ip: Option<IpAddr>) -> Self {
+        Self { id: id.into(), ip }
+    }
+
+    pub fn user(discord_id: i64, ip: Option<IpAddr>) -> Self {
+        Self::new(discord_id.to_string(), ip)
+    }
+
+    /// Background tasks such as ban expiry.
+    pub fn system() -> Self {
+        Self::new("system", None)
+    }
+}
+
+/// Something a user, an admin or the API did that moderators should be able to look back on.
+pub struct AuditEvent {
+    pub action: AuditAction,
+    pub actor: Actor,
+    pub discord_id: Option<i64>,
+    pub minecraft_name: Option<String>,
+    /// Human readable summaries for the webhook embed.
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub details: Option<String>,
+    /// The affected state as stored in the database.
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+#[derive(Serialize)]
+pub struct AuditRecord {
+    pub id: i64,
+    #[serde(with = "ts_seconds")]
+    pub created_at: DateTime<Utc>,
+    pub actor: String,
+    pub target_discord_id: Option<i64>,
+    pub action: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub ip: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(action: AuditAction, actor: &Actor) -> Self {
+        Self {
+            action,
+            actor: actor.clone(),
+            discord_id: None,
+            minecraft_name: None,
+            old_value: None,
+            new_value: None,
+            details: None,
+            before: None,
+            after: None,
+        }
+    }
+
+    pub fn user(mut self, discord_id: i64) -> Self {
+        self.discord_id = Some(discord_id);
+        self
+    }
+
+    pub fn minecraft_name(mut self, name: Option<String>) -> Self {
+        self.minecraft_name = name;
+        self
+    }
+
+    pub fn change(mut self, old_value: Option<String>, new_value: Option<String>) -> Self {
+        self.old_value = old_value;
+        self.new_value = new_value;
+        self
+    }
+
+    pub fn details(mut self, details: impl Into<String>) -> Self {
+        self.details = Some(details.into());
+        self
+    }
+
+    pub fn snapshot(mut self, before: Option<Value>, after: Option<Value>) -> Self {
+        self.before = before;
+        self.after = after;
+        self
+    }
+
+    fn embed(&self) -> Value {
+        let mut fields = Vec::new();
+
+        if let Some(discord_id) = self.discord_id {
+            fields.push(json!({ "name": "Discord user", "value": format!("<@{}> ({})", discord_id, discord_id), "inline": true }));
+        }
+        if let Some(name) = &self.minecraft_name {
+            fields.push(json!({ "name": "Minecraft name", "value": field_value(name), "inline": true }));
+        }
+        if let Some(old_value) = &self.old_value {
+            fields.push(json!({ "name": "Before", "value": field_value(old_value) }));
+        }
+        if let Some(new_value) = &self.new_value {
+            fields.push(json!({ "name": "After", "value": field_value(new_value) }));
+        }
+        if let Some(details) = &self.details {
+            fields.push(json!({ "name": "Details", "value": field_value(details) }));
+        }
+
+        let actor = match self.actor.id.parse::<i64>() {
+            Ok(discord_id) => format!("<@{}>", discord_id),
+            Err(_) => self.actor.id.clone(),
+        };
+        fields.push(json!({ "name": "By", "value": field_value(&actor), "inline": true }));
+
+        json!({
+            "title": self.action.title(),
+            "color": self.action.color(),
+            "fields": fields,
+            "timestamp": Utc::now().to_rfc3339(),
+        })
+    }
+}
+
+/// Cuts `value` down to what fits in an embed field, the full value stays in `audit_events`.
+fn field_value(value: &str) -> String {
+    if value.chars().count() <= MAX_FIELD_LENGTH {
+        return value.to_string();
+    }
+
+    let mut truncated = value.chars().take(MAX_FIELD_LENGTH - 1).collect::<String>();
+    truncated.push('…');
+    truncated
+}
+
+/// Stores the event in `audit_events`. Pass the transaction making the change so both are committed together.
+pub async fn record(conn: &mut PgConnection, event: &AuditEvent) -> Result<(), ApiError> {
+    query!("INSERT INTO audit_events (actor, target_discord_id, action, before, after, ip)
+            VALUES ($1, $2, $3, $4, $5, $6)",
+        event.actor.id, event.discord_id, event.action.as_str(), event.before, event.after,
+        event.actor.ip.map(|ip| ip.to_string()))
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Lists stored events, newest first, optionally only those targeting `discord_id` or of one `action`.
+pub async fn get_events(app: &State<App>, discord_id: Option<i64>, action: Option<&str>, limit: i64, offset: i64) -> Result<Vec<AuditRecord>, ApiError> {
+    Ok(query_as!(AuditRecord, "SELECT * FROM audit_events
+                               WHERE ($1::BIGINT IS NULL OR target_discord_id = $1)
+                                 AND ($2::TEXT IS NULL OR action = $2)
+                               ORDER BY created_at DESC, id DESC
+                               LIMIT $3 OFFSET $4", discord_id, action, limit, offset)
+        .fetch_all(&app.db)
+        .await?)
+}
+
+/// Posts the event to the `DISCORD_AUDIT_WEBHOOK_URL` webhook in the background, if one is configured.
+pub fn notify(app: &State<App>, event: &AuditEvent) {
+    let Some(url) = app.audit_webhook.clone() else {
+        return;
+    };
+
+    let https = app.https.clone();
+    let payload = json!({ "embeds": [event.embed()] });
+
+    rocket::tokio::spawn(async move {
+        let result = https.post(&url)
+            .json(&payload)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        if let Err(err) = result {
+            warn!("Failed to post to the audit log webhook \n {}", err);
+        }
+    });
+}
+
+/// Collects webhook payloads sent to the local stand-in, see `debug_webhook_receive`.
+#[derive(Default)]
+pub struct WebhookInbox(Mutex<Vec<Value>>);
+
+/// Stands in for a Discord webhook in debug builds: point `DISCORD_AUDIT_WEBHOOK_URL` at
+/// `http://localhost:8000/backend/debug/webhook` and read the received payloads back with a GET.
+#[post("/debug/webhook", data = "<payload>")]
+pub fn debug_webhook_receive(inbox: &State<WebhookInbox>, payload: Json<Value>) -> Status {
+    info!("Audit log webhook received {}", payload.0);
+    inbox.0.lock().unwrap_or_else(PoisonError::into_inner).push(payload.into_inner());
+
+    Status::NoContent
+}
+
+#[get("/debug/webhook")]
+pub fn debug_webhook_inbox(inbox: &State<WebhookInbox>) -> Json<Vec<Value>> {
+    Json(inbox.0.lock().unwrap_or_else(PoisonError::into_inner).clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::asynchronous::Client;
+
+    use super::*;
+
+    fn field<'a>(embed: &'a Value, name: &str) -> Option<&'a Value> {
+        embed["fields"].as_array()?.iter().find(|field| field["name"] == name).map(|field| &field["value"])
+    }
+
+    #[test]
+    fn embed_lists_the_event_fields() {
+        let event = AuditEvent::new(AuditAction::Relink, &Actor::user(42, None))
+            .user(7)
+            .minecraft_name(Some("Bob".to_string()))
+            .change(Some("Alice".to_string()), Some("Bob".to_string()));
+
+        let embed = event.embed();
+
+        assert_eq!(embed["title"], "Minecraft account changed");
+        assert_eq!(embed["color"], 0x5865F2);
+        assert_eq!(field(&embed, "Discord user"), Some(&json!("<@7> (7)")));
+        assert_eq!(field(&embed, "Minecraft name"), Some(&json!("Bob")));
+        assert_eq!(field(&embed, "Before"), Some(&json!("Alice")));
+        assert_eq!(field(&embed, "After"), Some(&json!("Bob")));
+        assert_eq!(field(&embed, "Details"), None);
+        assert_eq!(field(&embed, "By"), Some(&json!("<@42>")));
+    }
+
+    #[test]
+    fn embed_truncates_long_values() {
+        let event = AuditEvent::new(AuditAction::Ban, &Actor::new("api", None)).details("x".repeat(5000));
+
+        let embed = event.embed();
+        let details = field(&embed, "Details").and_then(Value::as_str).unwrap();
+
+        assert_eq!(details.chars().count(), MAX_FIELD_LENGTH);
+        assert!(details.ends_with('…'));
+        assert_eq!(field(&embed, "By"), Some(&json!("api")));
+    }
+
+    #[rocket::async_test]
+    async fn stand_in_webhook_keeps_payloads() {
+        let rocket = rocket::build()
+            .manage(WebhookInbox::default())
+            .mount("/backend/", routes![debug_webhook_receive, debug_webhook_inbox]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let payload = json!({ "embeds": [AuditEvent::new(AuditAction::Unban, &Actor::system()).user(7).embed()] });
+        let response = client.post("/backend/debug/webhook").json(&payload).dispatch().await;
+        assert_eq!(response.status(), Status::NoContent);
+
+        let inbox = client.get("/backend/debug/webhook").dispatch().await.into_json::<Vec<Value>>().await.unwrap();
+        assert_eq!(inbox, vec![payload]);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,237 @@
+use chrono::serde::{ts_seconds, ts_seconds_option};
+use chrono::{DateTime, Utc};
+use rocket::State;
+use serde::Serialize;
+use sqlx::{query, query_as};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::app::App;
+use crate::errors::ApiError;
+use crate::audit::{self, Actor, AuditAction, AuditEvent};
+use crate::{fetch_minecraft_profile, minecraft_profile};
+use crate::minecraft::{self, CommandResult};
+use crate::{roles, tasks};
+
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_BAN_MESSAGE: &str = "You have been banned from this server";
+
+#[derive(Serialize)]
+pub struct Ban {
+    pub id: i32,
+    pub user_id: i64,
+    pub reason: Option<String>,
+    pub issuer: String,
+    #[serde(with = "ts_seconds")]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "ts_seconds_option")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(with = "ts_seconds_option")]
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub revoked_by: Option<String>,
+    pub ingame: bool,
+}
+
+pub struct BanOptions {
+    pub reason: Option<String>,
+    /// `None` makes the ban permanent, otherwise it has to lie in the future.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Kick the player if they are currently online.
+    pub kick: bool,
+    /// Also ban the player through the server's own ban list.
+    pub ingame: bool,
+}
+
+#[derive(Serialize)]
+pub struct BanOutcome {
+    pub ban: Ban,
+    pub commands: Vec<CommandResult>,
+}
+
+#[derive(Serialize)]
+pub struct UnbanOutcome {
+    pub bans: Vec<Ban>,
+    pub commands: Vec<CommandResult>,
+}
+
+/// Records a new ban for `user_id`, marks the user as banned and removes them from every server whitelist.
+pub async fn ban_user(app: &State<App>, user_id: i64, issuer: &Actor, options: BanOptions) -> Result<BanOutcome, ApiError> {
+    if options.reason.as_deref().is_some_and(|reason| !minecraft::is_valid_reason(reason)) {
+        return Err(ApiError::BadRequest);
+    }
+
+    // An expiry in the past would take the player off the whitelist only for the expiry task to undo it.
+    if options.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return Err(ApiError::BadRequest);
+    }
+
+    let mut tx = app.db.begin().await?;
+
+    let ban = query_as!(Ban, "INSERT INTO bans (user_id, reason, issuer, expires_at, ingame)
+                              VALUES ($1, $2, $3, $4, $5)
+                              RETURNING *", user_id, options.reason, issuer.id, options.expires_at, options.ingame)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    let user = query!("UPDATE users SET banned = true WHERE discord_id = $1 RETURNING minecraft_uuid", user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    let expiry = match ban.expires_at {
+        Some(expires_at) => format!("Banned until <t:{}:f>", expires_at.timestamp()),
+        None => "Banned permanently".to_string(),
+    };
+    let event = AuditEvent::new(AuditAction::Ban, issuer)
+        .user(user_id)
+        .change(None, Some(expiry))
+        .details(ban.reason.clone().unwrap_or_else(|| DEFAULT_BAN_MESSAGE.to_string()))
+        .snapshot(None, Some(serde_json::to_value(&ban)?));
+
+    audit::record(&mut tx, &event).await?;
+    tx.commit().await?;
+
+    let commands = match user.minecraft_uuid {
+        Some(uuid) => enforce_ban(app, uuid, &options).await,
+        None => Vec::new(),
+    };
+
+    roles::sync_user(app, user_id).await;
+    audit::notify(app, &event.minecraft_name(minecraft_name(app, user.minecraft_uuid).await));
+
+    Ok(BanOutcome { ban, commands })
+}
+
+/// Revokes every active ban of `user_id`, lifts the banned flag and restores their whitelist entries.
+pub async fn unban_user(app: &State<App>, user_id: i64, revoked_by: &Actor) -> Result<UnbanOutcome, ApiError> {
+    let mut tx = app.db.begin().await?;
+
+    let bans = query_as!(Ban, "UPDATE bans SET revoked_at = NOW(), revoked_by = $2
+                               WHERE user_id = $1
+                                 AND revoked_at IS NULL
+                                 AND (expires_at IS NULL OR expires_at > NOW())
+                               RETURNING *", user_id, revoked_by.id)
+        .fetch_all(&mut *tx)
+        .await?;
+
+    let user = query!("UPDATE users SET banned = false WHERE discord_id = $1 RETURNING minecraft_uuid, is_admin, eligible", user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    let event = AuditEvent::new(AuditAction::Unban, revoked_by)
+        .user(user_id)
+        .details(format!("Revoked {} active ban(s)", bans.len()))
+        .snapshot(Some(serde_json::to_value(&bans)?), None);
+
+    audit::record(&mut tx, &event).await?;
+    tx.commit().await?;
+
+    let commands = match user.minecraft_uuid {
+        Some(uuid) => restore_access(app, uuid, user.is_admin, user.eligible, bans.iter().any(|ban| ban.ingame)).await,
+        None => Vec::new(),
+    };
+
+    roles::sync_user(app, user_id).await;
+    audit::notify(app, &event.minecraft_name(minecraft_name(app, user.minecraft_uuid).await));
+
+    Ok(UnbanOutcome { bans, commands })
+}
+
+pub async fn get_bans(app: &State<App>, user_id: i64) -> Result<Vec<Ban>, ApiError> {
+    Ok(query_as!(Ban, "SELECT * FROM bans WHERE user_id = $1 ORDER BY created_at DESC", user_id)
+        .fetch_all(&app.db)
+        .await?)
+}
+
+/// Clears the banned flag of every user whose bans have all expired and restores their whitelist entries,
+/// returning the Discord IDs of the users that were unbanned.
+pub async fn lift_expired_bans(app: &State<App>) -> Result<Vec<i64>, ApiError> {
+    let mut tx = app.db.begin().await?;
+
+    let lifted = query!(r#"UPDATE users SET banned = false
+                           WHERE banned = true
+                             AND NOT EXISTS (SELECT 1 FROM bans
+                                             WHERE bans.user_id = users.discord_id
+                                               AND revoked_at IS NULL
+                                               AND (expires_at IS NULL OR expires_at > NOW()))
+                           RETURNING discord_id, minecraft_uuid, is_admin, eligible,
+                                     EXISTS (SELECT 1 FROM bans
+                                             WHERE bans.user_id = users.discord_id
+                                               AND ingame = true) AS "pardon!""#)
+        .fetch_all(&mut *tx)
+        .await?;
+
+    let mut events = Vec::new();
+
+    for user in &lifted {
+        let event = AuditEvent::new(AuditAction::Unban, &Actor::system())
+            .user(user.discord_id)
+            .details("All bans expired");
+
+        audit::record(&mut tx, &event).await?;
+        events.push(event);
+    }
+
+    tx.commit().await?;
+
+    for (user, event) in lifted.iter().zip(events) {
+        if let Some(uuid) = user.minecraft_uuid {
+            restore_access(app, uuid, user.is_admin, user.eligible, user.pardon).await;
+        }
+
+        roles::sync_user(app, user.discord_id).await;
+        audit::notify(app, &event.minecraft_name(minecraft_name(app, user.minecraft_uuid).await));
+    }
+
+    Ok(lifted.into_iter().map(|row| row.discord_id).collect())
+}
+
+pub async fn expiry_task(app: App) {
+    tasks::run_every(app, EXPIRY_CHECK_INTERVAL, "lifting expired bans", |app| Box::pin(lift_expired_bans(app)), |lifted| {
+        if !lifted.is_empty() {
+            info!("Lifted {} expired ban(s)", lifted.len());
+        }
+    }).await
+}
+
+/// Best-effort name lookup for the audit log.
+async fn minecraft_name(app: &State<App>, uuid: Option<Uuid>) -> Option<String> {
+    minecraft_profile(app, uuid?).await.ok().map(|profile| profile.minecraft_username)
+}
+
+async fn enforce_ban(app: &State<App>, uuid: Uuid, options: &BanOptions) -> Vec<CommandResult> {
+    let username = match fetch_minecraft_profile(app, &uuid.to_string()).await {
+        Ok(profile) => profile.minecraft_username,
+        Err(err) => return vec![CommandResult::failed(format!("whitelist remove {}", uuid), err.to_string())],
+    };
+
+    let reason = options.reason.as_deref().unwrap_or(DEFAULT_BAN_MESSAGE);
+    let mut commands = minecraft::minecraft_whitelist_remove(app, &username).await;
+
+    if options.ingame {
+        commands.extend(minecraft::minecraft_ban(app, &username, reason).await);
+    } else if options.kick {
+        commands.extend(minecraft::minecraft_kick(app, &username, reason).await);
+    }
+
+    commands
+}
+
+/// Pardons the account if requested and puts it back on the whitelist, unless the user no longer meets the guild rules.
+async fn restore_access(app: &State<App>, uuid: Uuid, is_admin: bool, eligible: bool, pardon: bool) -> Vec<CommandResult> {
+    let username = match fetch_minecraft_profile(app, &uuid.to_string()).await {
+        Ok(profile) => profile.minecraft_username,
+        Err(err) => return vec![CommandResult::failed(format!("whitelist add {}", uuid), err.to_string())],
+    };
+
+    let mut commands = Vec::new();
+
+    if pardon {
+        commands.extend(minecraft::minecraft_pardon(app, &username).await);
+    }
+
+    if eligible {
+        commands.extend(minecraft::minecraft_whitelist(app, &username, is_admin).await);
+    }
+
+    commands
+}
\ No newline at end of file
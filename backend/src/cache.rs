@@ -0,0 +1,228 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// A size-bounded, in-memory cache for one kind of value. Entries expire after the cache's TTL and the
+/// least recently used entry is evicted once the cache is full.
+pub struct TypedCache<K, V> {
+    name: &'static str,
+    ttl: Duration,
+    /// How long a cached "not found" is kept, see `get_or_try_insert_optional_with`.
+    negative_ttl: Duration,
+    capacity: usize,
+    inner: Mutex<CacheInner<K, V>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct CacheInner<K, V> {
+    entries: HashMap<K, CacheEntry<V>>,
+    /// Keys ordered by last use, the first one is the next to be evicted.
+    recency: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+struct CacheEntry<V> {
+    value: V,
+    expires_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub size: usize,
+    pub capacity: usize,
+    pub ttl_secs: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> TypedCache<K, V> {
+    pub fn new(name: &'static str, ttl: Duration, capacity: usize) -> Self {
+        Self {
+            name,
+            ttl,
+            negative_ttl: ttl,
+            capacity: capacity.max(1),
+            inner: Mutex::new(CacheInner {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_negative_ttl(mut self, negative_ttl: Duration) -> Self {
+        self.negative_ttl = negative_ttl;
+        self
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut guard = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let inner = &mut *guard;
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let Some(entry) = inner.entries.get_mut(key) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+
+        let previous_use = entry.last_used;
+
+        if entry.expires_at <= Instant::now() {
+            inner.entries.remove(key);
+            inner.recency.remove(&previous_use);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        entry.last_used = tick;
+        let value = entry.value.clone();
+
+        inner.recency.remove(&previous_use);
+        inner.recency.insert(tick, key.clone());
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        Some(value)
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        self.insert_with_ttl(key, value, self.ttl);
+    }
+
+    fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) {
+        let mut guard = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let inner = &mut *guard;
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        if let Some(previous) = inner.entries.remove(&key) {
+            inner.recency.remove(&previous.last_used);
+        }
+
+        while inner.entries.len() >= self.capacity {
+            let Some((_, oldest)) = inner.recency.pop_first() else {
+                break;
+            };
+            inner.entries.remove(&oldest);
+        }
+
+        inner.recency.insert(tick, key.clone());
+        inner.entries.insert(key, CacheEntry { value, expires_at: Instant::now() + ttl, last_used: tick });
+    }
+
+    /// Returns the cached value for `key`, or runs `fetch` and caches its result if it succeeds.
+    pub async fn get_or_try_insert_with<F, Fut, E>(&self, key: K, fetch: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        if let Some(value) = self.get(&key) {
+            return Ok(value);
+        }
+
+        let value = fetch().await?;
+        self.insert(key, value.clone());
+
+        Ok(value)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+
+        CacheStats {
+            name: self.name,
+            size: inner.entries.len(),
+            capacity: self.capacity,
+            ttl_secs: self.ttl.as_secs(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> TypedCache<K, Option<V>> {
+    /// Like `get_or_try_insert_with`, but a `None` from `fetch` is only cached for the negative TTL, so a
+    /// lookup that found nothing is retried sooner than one that did.
+    pub async fn get_or_try_insert_optional_with<F, Fut, E>(&self, key: K, fetch: F) -> Result<Option<V>, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<V>, E>>,
+    {
+        if let Some(value) = self.get(&key) {
+            return Ok(value);
+        }
+
+        let value = fetch().await?;
+        let ttl = if value.is_some() { self.ttl } else { self.negative_ttl };
+        self.insert_with_ttl(key, value.clone(), ttl);
+
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = TypedCache::new("test", TTL, 2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+
+        // Using "a" makes "b" the least recently used entry.
+        assert_eq!(cache.get(&"a"), Some(1));
+        cache.insert("c", 3);
+
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"c"), Some(3));
+        assert_eq!(cache.stats().size, 2);
+    }
+
+    #[test]
+    fn replacing_a_key_does_not_evict() {
+        let cache = TypedCache::new("test", TTL, 2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        cache.insert("a", 3);
+
+        assert_eq!(cache.get(&"a"), Some(3));
+        assert_eq!(cache.get(&"b"), Some(2));
+    }
+
+    #[test]
+    fn expires_after_ttl() {
+        let cache = TypedCache::new("test", Duration::ZERO, 2);
+        cache.insert("a", 1);
+
+        assert_eq!(cache.get(&"a"), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.size, stats.hits, stats.misses), (0, 0, 1));
+    }
+
+    #[rocket::async_test]
+    async fn not_found_uses_negative_ttl() {
+        let cache = TypedCache::<&str, Option<i32>>::new("test", TTL, 2).with_negative_ttl(Duration::ZERO);
+
+        let found = cache.get_or_try_insert_optional_with("a", || async { Ok::<_, ()>(Some(1)) }).await;
+        let missing = cache.get_or_try_insert_optional_with("b", || async { Ok::<_, ()>(None) }).await;
+        assert_eq!((found, missing), (Ok(Some(1)), Ok(None)));
+
+        assert_eq!(cache.get(&"a"), Some(Some(1)));
+        assert_eq!(cache.get(&"b"), None);
+    }
+}
\ No newline at end of file
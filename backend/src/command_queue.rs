@@ -0,0 +1,137 @@
+use chrono::serde::{ts_seconds, ts_seconds_option};
+use chrono::{DateTime, Utc};
+use rocket::State;
+use serde::Serialize;
+use sqlx::{query, query_as, PgConnection};
+use std::time::Duration;
+
+use crate::app::App;
+use crate::errors::ApiError;
+use crate::minecraft;
+use crate::{servers, tasks};
+
+const RETRY_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const BASE_BACKOFF_SECS: i64 = 30;
+const MAX_BACKOFF_SECS: i64 = 3600;
+/// Commands that still fail after this many attempts are abandoned and only show up in the admin view.
+pub const MAX_ATTEMPTS: i32 = 10;
+
+#[derive(Serialize)]
+pub struct PendingCommand {
+    pub id: i32,
+    pub username: String,
+    pub command: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    #[serde(with = "ts_seconds")]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "ts_seconds")]
+    pub next_attempt_at: DateTime<Utc>,
+    #[serde(with = "ts_seconds_option")]
+    pub completed_at: Option<DateTime<Utc>>,
+    pub server_id: Option<i32>,
+}
+
+pub fn backoff_secs(attempts: i32) -> i64 {
+    BASE_BACKOFF_SECS.saturating_mul(1i64 << attempts.clamp(0, 16)).min(MAX_BACKOFF_SECS)
+}
+
+/// Queues a command that failed on its first attempt so the retry worker picks it up later.
+pub async fn enqueue(app: &State<App>, server_id: i32, username: &str, command: &str, error: Option<&str>) -> Result<(), ApiError> {
+    query!("INSERT INTO pending_commands (server_id, username, command, last_error, next_attempt_at)
+            VALUES ($1, $2, $3, $4, NOW() + make_interval(secs => $5))",
+        server_id, username, command, error, backoff_secs(1) as f64)
+        .execute(&app.db)
+        .await?;
+
+    Ok(())
+}
+
+/// Drops queued commands for `username` on a server, so a stale retry can't undo a newer whitelist change.
+pub async fn supersede(app: &State<App>, server_id: i32, username: &str) -> Result<(), ApiError> {
+    query!("UPDATE pending_commands SET status = 'superseded', completed_at = NOW()
+            WHERE server_id = $1 AND LOWER(username) = LOWER($2) AND status IN ('pending', 'abandoned')", server_id, username)
+        .execute(&app.db)
+        .await?;
+
+    Ok(())
+}
+
+/// Lists queued commands that haven't gone through yet, including abandoned ones.
+pub async fn get_stuck_commands(app: &State<App>) -> Result<Vec<PendingCommand>, ApiError> {
+    Ok(query_as!(PendingCommand, "SELECT * FROM pending_commands
+                                  WHERE status IN ('pending', 'abandoned')
+                                  ORDER BY created_at")
+        .fetch_all(&app.db)
+        .await?)
+}
+
+/// Puts a queued command back at the front of the queue with a fresh attempt budget.
+pub async fn reschedule(conn: &mut PgConnection, id: i32) -> Result<PendingCommand, ApiError> {
+    query_as!(PendingCommand, "UPDATE pending_commands SET status = 'pending', attempts = 0, next_attempt_at = NOW()
+                               WHERE id = $1 AND status IN ('pending', 'abandoned')
+                               RETURNING *", id)
+        .fetch_optional(conn)
+        .await?
+        .ok_or(ApiError::NotFound)
+}
+
+pub async fn discard(conn: &mut PgConnection, id: i32) -> Result<PendingCommand, ApiError> {
+    query_as!(PendingCommand, "UPDATE pending_commands SET status = 'superseded', completed_at = NOW()
+                               WHERE id = $1 AND status IN ('pending', 'abandoned')
+                               RETURNING *", id)
+        .fetch_optional(conn)
+        .await?
+        .ok_or(ApiError::NotFound)
+}
+
+async fn retry_due_commands(app: &State<App>) -> Result<usize, ApiError> {
+    let due = query_as!(PendingCommand, "SELECT * FROM pending_commands
+                                         WHERE status = 'pending' AND next_attempt_at <= NOW()
+                                         ORDER BY id")
+        .fetch_all(&app.db)
+        .await?;
+
+    let servers = servers::get_enabled_servers(app).await?;
+    let mut completed = 0;
+
+    for command in due {
+        // Commands for disabled servers stay queued until the server is enabled again.
+        let Some(server) = servers.iter().find(|server| Some(server.id) == command.server_id) else {
+            continue;
+        };
+
+        match minecraft::send_command(app, server, &command.command).await {
+            Ok(_) => {
+                query!("UPDATE pending_commands SET status = 'completed', attempts = attempts + 1, completed_at = NOW()
+                        WHERE id = $1", command.id)
+                    .execute(&app.db)
+                    .await?;
+
+                completed += 1;
+            },
+            Err(err) => {
+                let attempts = command.attempts + 1;
+                let status = if attempts >= MAX_ATTEMPTS { "abandoned" } else { "pending" };
+
+                query!("UPDATE pending_commands
+                        SET status = $2, attempts = $3, last_error = $4, next_attempt_at = NOW() + make_interval(secs => $5)
+                        WHERE id = $1",
+                    command.id, status, attempts, err.to_string(), backoff_secs(attempts) as f64)
+                    .execute(&app.db)
+                    .await?;
+            },
+        }
+    }
+
+    Ok(completed)
+}
+
+pub async fn retry_task(app: App) {
+    tasks::run_every(app, RETRY_CHECK_INTERVAL, "retrying queued console commands", |app| Box::pin(retry_due_commands(app)), |completed| {
+        if completed > 0 {
+            info!("Retried {} queued console command(s)", completed);
+        }
+    }).await
+}
\ No newline at end of file
@@ -0,0 +1,44 @@
+use crate::app::App;
+use crate::errors::ApiError;
+use crate::minecraft::Whitelist;
+use crate::servers::{MinecraftServer, BACKEND_PTERODACTYL, BACKEND_RCON};
+
+mod pterodactyl;
+mod rcon;
+
+pub use pterodactyl::PterodactylControl;
+pub use rcon::RconControl;
+
+/// A way of talking to a running Minecraft server.
+#[rocket::async_trait]
+pub trait ServerControl: Send + Sync {
+    /// Runs a console command, returning the server's reply when the backend provides one.
+    async fn send_command(&self, command: &str) -> Result<String, ApiError>;
+
+    /// Reads the server's current whitelist.
+    async fn read_whitelist(&self) -> Result<Whitelist, ApiError>;
+}
+
+/// Builds the control backend configured for `server`.
+pub fn for_server(app: &App, server: &MinecraftServer) -> Result<Box<dyn ServerControl>, ApiError> {
+    match server.backend.as_str() {
+        BACKEND_PTERODACTYL => {
+            let client = app.pterodactyl.clone().ok_or_else(|| ApiError::ServerControl(
+                "Pterodactyl is not configured, set PTERODACTYL_URL and PTERODACTYL_APIKEY".to_string()
+            ))?;
+            let server_id = server.pterodactyl_id.clone().ok_or_else(|| ApiError::ServerControl(
+                format!("Server {} has no pterodactyl_id", server.name)
+            ))?;
+
+            Ok(Box::new(PterodactylControl::new(client, server_id)))
+        },
+        BACKEND_RCON => {
+            let (Some(address), Some(password)) = (server.rcon_address.clone(), server.rcon_password.clone()) else {
+                return Err(ApiError::ServerControl(format!("Server {} has no rcon_address or rcon_password", server.name)));
+            };
+
+            Ok(Box::new(RconControl::new(app.https.clone(), address, password)))
+        },
+        backend => Err(ApiError::ServerControl(format!("Unknown server backend {}", backend))),
+    }
+}
\ No newline at end of file
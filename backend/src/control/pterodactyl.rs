@@ -0,0 +1,40 @@
+use std::sync::Arc;
+
+use pterodactyl_api::client::Client;
+
+use crate::control::ServerControl;
+use crate::errors::ApiError;
+use crate::minecraft::Whitelist;
+
+/// Controls a server through the Pterodactyl panel's client API.
+pub struct PterodactylControl {
+    client: Arc<Client>,
+    server_id: String,
+}
+
+impl PterodactylControl {
+    pub fn new(client: Arc<Client>, server_id: String) -> Self {
+        Self { client, server_id }
+    }
+}
+
+#[rocket::async_trait]
+impl ServerControl for PterodactylControl {
+    async fn send_command(&self, command: &str) -> Result<String, ApiError> {
+        self.client.get_server(&self.server_id)
+            .send_command(command)
+            .await?;
+
+        // The panel only queues the command on the console, it never hands back the output.
+        Ok(String::new())
+    }
+
+    async fn read_whitelist(&self) -> Result<Whitelist, ApiError> {
+        let contents = self.client.get_server(&self.server_id)
+            .file_contents_text("whitelist.json")
+            .await?;
+
+        // whitelist.json already holds the UUIDs, so every entry resolves.
+        Ok(Whitelist { entries: serde_json::from_str(&contents)?, unresolved: Vec::new() })
+    }
+}
\ No newline at end of file
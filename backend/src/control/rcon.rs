@@ -0,0 +1,231 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+use crate::control::ServerControl;
+use crate::errors::ApiError;
+use crate::minecraft::{Whitelist, WhitelistEntry};
+
+const PACKET_RESPONSE: i32 = 0;
+const PACKET_COMMAND: i32 = 2;
+const PACKET_LOGIN: i32 = 3;
+
+const LOGIN_ID: i32 = 1;
+const COMMAND_ID: i32 = 2;
+/// Sent after the command so the end of a reply split over several packets can be detected.
+const TERMINATOR_ID: i32 = 3;
+
+/// Largest packet we accept, well above the 4096 byte payloads the vanilla server sends.
+const MAX_PACKET_SIZE: i32 = 1 << 20;
+const RCON_TIMEOUT: Duration = Duration::from_secs(10);
+/// The Mojang bulk lookup endpoint refuses more names than this per request.
+const BULK_LOOKUP_SIZE: usize = 10;
+
+/// Controls a server directly over the Source RCON protocol, for setups without a panel.
+pub struct RconControl {
+    https: reqwest::Client,
+    address: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct BulkProfile {
+    id: Uuid,
+    name: String,
+}
+
+struct Packet {
+    id: i32,
+    body: String,
+}
+
+impl RconControl {
+    pub fn new(https: reqwest::Client, address: String, password: String) -> Self {
+        Self { https, address, password }
+    }
+
+    async fn exchange(&self, command: &str) -> Result<String, ApiError> {
+        let mut stream = TcpStream::connect(&self.address).await?;
+
+        write_packet(&mut stream, LOGIN_ID, PACKET_LOGIN, &self.password).await?;
+        if read_packet(&mut stream).await?.id == -1 {
+            return Err(ApiError::ServerControl("RCON authentication failed".to_string()));
+        }
+
+        write_packet(&mut stream, COMMAND_ID, PACKET_COMMAND, command).await?;
+        write_packet(&mut stream, TERMINATOR_ID, PACKET_RESPONSE, "").await?;
+
+        let mut response = String::new();
+        loop {
+            let packet = read_packet(&mut stream).await?;
+            match packet.id {
+                COMMAND_ID => response.push_str(&packet.body),
+                TERMINATOR_ID => return Ok(response),
+                id => return Err(ApiError::ServerControl(format!("Unexpected RCON packet id {}", id))),
+            }
+        }
+    }
+
+    /// Resolves whitelisted names to UUIDs, since the `whitelist list` command only prints names.
+    async fn lookup_uuids(&self, names: Vec<String>) -> Result<Whitelist, ApiError> {
+        let mut entries = Vec::new();
+
+        for chunk in names.chunks(BULK_LOOKUP_SIZE) {
+            let profiles = self.https.post("https://api.minecraftservices.com/minecraft/profile/lookup/bulk/byname")
+                .json(chunk)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<Vec<BulkProfile>>()
+                .await?;
+
+            entries.extend(profiles.into_iter().map(|profile| WhitelistEntry { uuid: profile.id, name: profile.name }));
+        }
+
+        // Names are matched case-insensitively, the lookup returns them in their current casing.
+        let unresolved = names.into_iter()
+            .filter(|name| !entries.iter().any(|entry| entry.name.eq_ignore_ascii_case(name)))
+            .collect::<Vec<String>>();
+
+        Ok(Whitelist { entries, unresolved })
+    }
+}
+
+#[rocket::async_trait]
+impl ServerControl for RconControl {
+    async fn send_command(&self, command: &str) -> Result<String, ApiError> {
+        timeout(RCON_TIMEOUT, self.exchange(command))
+            .await
+            .map_err(|_| ApiError::ServerControl(format!("RCON request to {} timed out", self.address)))?
+    }
+
+    async fn read_whitelist(&self) -> Result<Whitelist, ApiError> {
+        let response = self.send_command("whitelist list").await?;
+
+        // "There are 2 whitelisted player(s): Alice, Bob" or "There are no whitelisted players"
+        let names = match response.split_once(':') {
+            Some((_, names)) => names.split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect::<Vec<String>>(),
+            None => Vec::new(),
+        };
+
+        self.lookup_uuids(names).await
+    }
+}
+
+async fn write_packet(stream: &mut TcpStream, id: i32, kind: i32, body: &str) -> Result<(), ApiError> {
+    let length = (body.len() + 10) as i32;
+
+    let mut buffer = Vec::with_capacity(length as usize + 4);
+    buffer.extend_from_slice(&length.to_le_bytes());
+    buffer.extend_from_slice(&id.to_le_bytes());
+    buffer.extend_from_slice(&kind.to_le_bytes());
+    buffer.extend_from_slice(body.as_bytes());
+    buffer.extend_from_slice(&[0, 0]);
+
+    stream.write_all(&buffer).await?;
+
+    Ok(())
+}
+
+async fn read_packet(stream: &mut TcpStream) -> Result<Packet, ApiError> {
+    let length = stream.read_i32_le().await?;
+    if !(10..=MAX_PACKET_SIZE).contains(&length) {
+        return Err(ApiError::ServerControl(format!("Invalid RCON packet length {}", length)));
+    }
+
+    let id = stream.read_i32_le().await?;
+    let _kind = stream.read_i32_le().await?;
+
+    let mut body = vec![0; length as usize - 8];
+    stream.read_exact(&mut body).await?;
+    body.truncate(body.len() - 2);
+
+    Ok(Packet { id, body: String::from_utf8_lossy(&body).into_owned() })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const PASSWORD: &str = "hunter2";
+
+    /// Answers one login and one command like a vanilla server would, splitting the reply over `parts` packets.
+    async fn serve(listener: TcpListener, parts: Vec<&'static str>) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let login = read_packet(&mut stream).await.unwrap();
+        let id = if login.body == PASSWORD { login.id } else { -1 };
+        write_packet(&mut stream, id, PACKET_COMMAND, "").await.unwrap();
+        if id == -1 {
+            return;
+        }
+
+        let command = read_packet(&mut stream).await.unwrap();
+        let terminator = read_packet(&mut stream).await.unwrap();
+        for part in parts {
+            write_packet(&mut stream, command.id, PACKET_RESPONSE, part).await.unwrap();
+        }
+        write_packet(&mut stream, terminator.id, PACKET_RESPONSE, "").await.unwrap();
+    }
+
+    async fn control(parts: Vec<&'static str>, password: &str) -> RconControl {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve(listener, parts));
+
+        RconControl::new(reqwest::Client::new(), address, password.to_string())
+    }
+
+    #[rocket::async_test]
+    async fn packets_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        write_packet(&mut client, 42, PACKET_COMMAND, "whitelist list").await.unwrap();
+        write_packet(&mut client, 43, PACKET_RESPONSE, "").await.unwrap();
+
+        let packet = read_packet(&mut server).await.unwrap();
+        assert_eq!(packet.id, 42);
+        assert_eq!(packet.body, "whitelist list");
+
+        let empty = read_packet(&mut server).await.unwrap();
+        assert_eq!(empty.id, 43);
+        assert_eq!(empty.body, "");
+    }
+
+    #[rocket::async_test]
+    async fn rejects_invalid_length() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        client.write_all(&4i32.to_le_bytes()).await.unwrap();
+
+        assert!(matches!(read_packet(&mut server).await, Err(ApiError::ServerControl(_))));
+    }
+
+    #[rocket::async_test]
+    async fn joins_split_replies_until_terminator() {
+        let control = control(vec!["There are 2 whitelisted player(s): ", "Alice, Bob"], PASSWORD).await;
+
+        let response = control.send_command("whitelist list").await.unwrap();
+        assert_eq!(response, "There are 2 whitelisted player(s): Alice, Bob");
+    }
+
+    #[rocket::async_test]
+    async fn fails_on_wrong_password() {
+        let control = control(Vec::new(), "wrong").await;
+
+        assert!(matches!(control.send_command("whitelist list").await, Err(ApiError::ServerControl(_))));
+    }
+}
\ No newline at end of file
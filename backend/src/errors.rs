@@ -1,11 +1,16 @@
 use std::io::Cursor;
 
-use rocket::http::Status;
+use chrono::{DateTime, Utc};
+use rocket::http::{ContentType, Header, Status};
 use rocket::response::Responder;
+use rocket::serde::json::Json;
 use rocket::{Request, Response};
+use serde::Serialize;
+use serde_json::{json, Value};
 use thiserror::Error;
+use uuid::Uuid;
 
-#[allow(dead_code)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 #[derive(Debug, Error)]
 pub enum ApiError {
     #[error("SQL error: {0}")]
@@ -14,14 +19,32 @@ pub enum ApiError {
     Request(#[from] reqwest::Error),
     #[error("OAuth token error: {0}")]
     TokenError(#[from] rocket_oauth2::Error),
+    #[error("Pterodactyl error: {0}")]
+    Pterodactyl(#[from] pterodactyl_api::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Server control error: {0}")]
+    ServerControl(String),
+    #[error("Discord error: {0}")]
+    Discord(String),
     #[error("You're not authorized!")]
     Unauthorized,
+    #[error("Not eligible: {0}")]
+    NotEligible(String),
     #[error("You are being rate limited, please try again later!")]
     RateLimited,
+    #[error("Linked account changed too recently, next change allowed at {0}")]
+    LinkChangeLimited(DateTime<Utc>),
+    #[error("You need to be logged in to do that!")]
+    NotLoggedIn,
     #[error("Attempted to get a non-none value but found none")]
     OptionError,
     #[error("Bad Request")]
     BadRequest,
+    #[error("The requested resource was not found")]
+    NotFound,
     #[error("Collision issue in passed value")]
     CollisionError,
     #[error("Attempted to parse a number to an integer but errored out: {0}")]
@@ -32,31 +55,127 @@ pub enum ApiError {
     FromRequestPartsError(#[from] std::convert::Infallible),
 }
 
+/// The JSON body of every error response.
+#[derive(Serialize)]
+pub struct ErrorBody {
+    /// Stable, machine-readable error code.
+    pub code: &'static str,
+    /// Human-readable message that can be shown to the user as is.
+    pub message: String,
+    pub details: Option<Value>,
+}
+
+const INTERNAL_MESSAGE: &str = "Something went wrong on our end, please try again later!";
+
+impl ApiError {
+    pub fn status(&self) -> Status {
+        match self {
+            Self::SQL(_) | Self::Json(_) | Self::Io(_) => Status::InternalServerError,
+            Self::ParseIntError(_) | Self::ParseStringAsIntError(_) | Self::FromRequestPartsError(_) | Self::OptionError => Status::InternalServerError,
+            Self::Request(_) | Self::TokenError(_) | Self::Pterodactyl(_) | Self::ServerControl(_) | Self::Discord(_) => Status::BadGateway,
+            Self::Unauthorized | Self::NotLoggedIn => Status::Unauthorized,
+            Self::NotEligible(_) => Status::Forbidden,
+            Self::RateLimited | Self::LinkChangeLimited(_) => Status::TooManyRequests,
+            Self::BadRequest => Status::BadRequest,
+            Self::NotFound => Status::NotFound,
+            Self::CollisionError => Status::Conflict,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::SQL(_) => "database_error",
+            Self::Request(_) | Self::Discord(_) => "upstream_error",
+            Self::TokenError(_) => "oauth_error",
+            Self::Pterodactyl(_) | Self::ServerControl(_) => "server_control_error",
+            Self::Json(_) | Self::Io(_) | Self::ParseIntError(_) | Self::ParseStringAsIntError(_) | Self::FromRequestPartsError(_) | Self::OptionError => "internal_error",
+            Self::Unauthorized => "unauthorized",
+            Self::NotEligible(_) => "not_eligible",
+            Self::RateLimited => "rate_limited",
+            Self::LinkChangeLimited(_) => "link_change_limited",
+            Self::NotLoggedIn => "not_logged_in",
+            Self::BadRequest => "bad_request",
+            Self::NotFound => "not_found",
+            Self::CollisionError => "conflict",
+        }
+    }
+}
+
 impl<'r> Responder<'r, 'static> for ApiError {
-    fn respond_to(self, _request: &'r Request<'_>) -> rocket::response::Result<'static> {
-        let response = match self {
-            Self::SQL(e) => (Status::InternalServerError, e.to_string()),
-            Self::Request(e) => (Status::InternalServerError, e.to_string()),
-            Self::TokenError(e) => (Status::InternalServerError, e.to_string()),
-            Self::Unauthorized => (Status::Unauthorized, "Unauthorized!".to_string()),
-            Self::RateLimited => (Status::TooManyRequests, "Rate-limited!".to_string()),
-            Self::OptionError => (
-                Status::InternalServerError,
-                "Attempted to get a non-none value but found none".to_string(),
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let status = self.status();
+        let code = self.code();
+
+        let retry_after = match &self {
+            Self::LinkChangeLimited(next_change_at) => Some((*next_change_at - Utc::now()).num_seconds().max(1)),
+            _ => None,
+        };
+
+        // Internal errors can contain queries, URLs or tokens, so they only end up in the log.
+        if status.code >= 500 {
+            let correlation_id = Uuid::new_v4();
+            error!("A unknown error occurred while handling {} {} [{}] \n {}", request.method(), request.uri(), correlation_id, self);
+
+            let body = ErrorBody {
+                code,
+                message: INTERNAL_MESSAGE.to_string(),
+                details: Some(json!({ "correlation_id": correlation_id })),
+            };
+
+            return build_response(status, &body, Some(correlation_id), None);
+        }
+
+        let (message, details) = match self {
+            Self::Unauthorized => ("You're not authorized!".to_string(), None),
+            Self::NotEligible(message) => (message, None),
+            Self::RateLimited => ("You are being rate limited, please try again later!".to_string(), None),
+            Self::LinkChangeLimited(next_change_at) => (
+                format!("You can change your linked Minecraft account again on {} UTC!", next_change_at.format("%Y-%m-%d at %H:%M")),
+                Some(json!({ "next_change_at": next_change_at.timestamp() })),
             ),
-            Self::BadRequest => (Status::BadRequest, "Bad Request!".to_string()),
-            Self::CollisionError => (Status::InternalServerError, "Collision!".to_string()),
-            Self::ParseIntError(e) => (Status::InternalServerError, e.to_string()),
-            Self::ParseStringAsIntError(e) => (Status::InternalServerError, e.to_string()),
-            Self::FromRequestPartsError(e) => (Status::InternalServerError, e.to_string()),
+            Self::NotLoggedIn => ("You need to be logged in to do that!".to_string(), None),
+            Self::BadRequest => ("Bad Request!".to_string(), None),
+            Self::NotFound => ("Not Found!".to_string(), None),
+            Self::CollisionError => ("That is already in use!".to_string(), None),
+            err => (err.to_string(), None),
         };
 
-        let (status, message) = response;
-        let response = Response::build()
-            .status(status)
-            .sized_body(message.len(), Cursor::new(message))
-            .finalize();
+        build_response(status, &ErrorBody { code, message, details }, None, retry_after)
+    }
+}
+
+fn build_response(status: Status, body: &ErrorBody, correlation_id: Option<Uuid>, retry_after: Option<i64>) -> rocket::response::Result<'static> {
+    let body = serde_json::to_string(body).map_err(|_| Status::InternalServerError)?;
+
+    let mut response = Response::build();
+    response.status(status)
+        .header(ContentType::JSON)
+        .sized_body(body.len(), Cursor::new(body));
 
-        Ok(response)
+    if let Some(correlation_id) = correlation_id {
+        response.header(Header::new("X-Correlation-ID", correlation_id.to_string()));
     }
+
+    if let Some(retry_after) = retry_after {
+        response.header(Header::new("Retry-After", retry_after.to_string()));
+    }
+
+    Ok(response.finalize())
+}
+
+/// Answers requests that failed before reaching a handler, e.g. a rejected guard or an unknown route,
+/// with the same JSON body handlers use.
+#[catch(default)]
+pub fn default_catcher(status: Status, _request: &Request) -> (Status, Json<ErrorBody>) {
+    let (code, message) = match status.code {
+        400 | 422 => ("bad_request", "Bad Request!"),
+        401 => ("unauthorized", "You're not authorized!"),
+        403 => ("forbidden", "You're not allowed to do that!"),
+        404 => ("not_found", "Not Found!"),
+        429 => ("rate_limited", "You are being rate limited, please try again later!"),
+        code if code >= 500 => ("internal_error", INTERNAL_MESSAGE),
+        _ => ("error", status.reason_lossy()),
+    };
+
+    (status, Json(ErrorBody { code, message: message.to_string(), details: None }))
 }
\ No newline at end of file
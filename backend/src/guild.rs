@@ -0,0 +1,228 @@
+use reqwest::{RequestBuilder, StatusCode};
+use rocket::State;
+use serde::Deserialize;
+use sqlx::query;
+use std::env;
+use std::time::Duration;
+
+use crate::app::App;
+use crate::errors::ApiError;
+use crate::{roles, tasks};
+use crate::{minecraft, minecraft_profile};
+
+const ELIGIBILITY_CHECK_INTERVAL: Duration = Duration::from_secs(600);
+/// Each linked user is checked again once their last check is older than this.
+const RECHECK_AFTER_HOURS: i32 = 6;
+const CHECK_BATCH_SIZE: i64 = 100;
+/// Lets a user's own token read their guild membership.
+const MEMBER_SCOPE: &str = "guilds.members.read";
+
+/// Whitelist eligibility rules, users have to be in `guild_id` and hold `required_role` if one is set.
+#[derive(Clone)]
+pub struct GuildRules {
+    pub guild_id: String,
+    pub required_role: Option<String>,
+}
+
+impl GuildRules {
+    /// Reads `DISCORD_GUILD_ID` and `DISCORD_REQUIRED_ROLE_ID`, everyone is eligible when no guild is configured.
+    pub fn from_env() -> Option<Self> {
+        Some(Self {
+            guild_id: env::var("DISCORD_GUILD_ID").ok()?,
+            required_role: env::var("DISCORD_REQUIRED_ROLE_ID").ok(),
+        })
+    }
+
+    fn eligibility(&self, member: Option<GuildMember>) -> Eligibility {
+        match (member, &self.required_role) {
+            (None, _) => Eligibility::NotMember,
+            (Some(member), Some(role)) if !member.roles.contains(role) => Eligibility::MissingRole,
+            (Some(_), _) => Eligibility::Eligible,
+        }
+    }
+}
+
+/// OAuth scopes to log users in with, guild membership is only needed to check the eligibility rules.
+pub fn required_scopes(app: &App) -> &'static [&'static str] {
+    match app.guild {
+        Some(_) => &["identify", MEMBER_SCOPE],
+        None => &["identify"],
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Eligibility {
+    Eligible,
+    NotMember,
+    MissingRole,
+}
+
+#[derive(Deserialize)]
+struct GuildMember {
+    roles: Vec<String>,
+}
+
+async fn fetch_member(request: RequestBuilder) -> Result<Option<GuildMember>, ApiError> {
+    let response = request.send().await?;
+
+    match response.status() {
+        StatusCode::NOT_FOUND => Ok(None),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(ApiError::Unauthorized),
+        _ => Ok(Some(response.error_for_status()?.json::<GuildMember>().await?)),
+    }
+}
+
+/// Checks the rules with the user's own OAuth token, which needs the `guilds.members.read` scope.
+pub async fn check_with_token(app: &State<App>, access_token: &str) -> Result<Eligibility, ApiError> {
+    let Some(rules) = &app.guild else {
+        return Ok(Eligibility::Eligible);
+    };
+
+    let request = app.https.get(format!("https://discord.com/api/users/@me/guilds/{}/member", rules.guild_id))
+        .header("Authorization", format!("Bearer {}", access_token));
+
+    match fetch_member(request).await {
+        Ok(member) => Ok(rules.eligibility(member)),
+        // Sessions from before the guilds.members.read scope was requested can't see memberships.
+        Err(ApiError::Unauthorized) => Err(ApiError::NotEligible(
+            "We couldn't check your Discord server membership, please log out and log in again!".to_string()
+        )),
+        Err(err) => Err(err),
+    }
+}
+
+/// The link-time check: through the bot when there is one, since it doesn't depend on the scopes of the
+/// user's session, otherwise with the session's own token.
+pub async fn check_for_link(app: &State<App>, discord_id: i64, access_token: &str) -> Result<Eligibility, ApiError> {
+    if app.discord_bot.is_some() {
+        if let Some(eligibility) = check_user(app, discord_id).await? {
+            return Ok(eligibility);
+        }
+    }
+
+    check_with_token(app, access_token).await
+}
+
+/// Checks the rules through the bot, falling back to the user's newest session token without one. Returns
+/// `None` if neither is available.
+async fn check_user(app: &State<App>, discord_id: i64) -> Result<Option<Eligibility>, ApiError> {
+    let Some(rules) = &app.guild else {
+        return Ok(Some(Eligibility::Eligible));
+    };
+
+    if let Some(bot) = &app.discord_bot {
+        let request = bot.get(format!("https://discord.com/api/guilds/{}/members/{}", rules.guild_id, discord_id));
+        return Ok(Some(rules.eligibility(fetch_member(request).await?)));
+    }
+
+    let session = query!("SELECT access_token FROM sessions
+                          WHERE user_id = $1 AND expired = false AND expires_at > NOW()
+                          ORDER BY expires_at DESC
+                          LIMIT 1", discord_id)
+        .fetch_optional(&app.db)
+        .await?;
+
+    match session {
+        Some(session) => Ok(Some(check_with_token(app, &session.access_token).await?)),
+        None => Ok(None),
+    }
+}
+
+async fn set_eligible(app: &State<App>, discord_id: i64, eligible: bool) -> Result<(), ApiError> {
+    query!("UPDATE users SET eligible = $2, eligibility_checked_at = NOW() WHERE discord_id = $1", discord_id, eligible)
+        .execute(&app.db)
+        .await?;
+
+    Ok(())
+}
+
+async fn mark_checked(app: &State<App>, discord_id: i64) -> Result<(), ApiError> {
+    query!("UPDATE users SET eligibility_checked_at = NOW() WHERE discord_id = $1", discord_id)
+        .execute(&app.db)
+        .await?;
+
+    Ok(())
+}
+
+/// Stores the outcome of a check. When it differs from the stored one, the currently linked account is taken
+/// off or put back on the whitelist and the linked roles are synced. Returns whether eligibility changed.
+pub async fn apply_eligibility(app: &State<App>, discord_id: i64, eligible: bool) -> Result<bool, ApiError> {
+    let Some(user) = query!("SELECT minecraft_uuid, is_admin, banned, eligible FROM users WHERE discord_id = $1", discord_id)
+        .fetch_optional(&app.db)
+        .await? else {
+        return Err(ApiError::NotFound);
+    };
+
+    set_eligible(app, discord_id, eligible).await?;
+
+    if eligible == user.eligible {
+        return Ok(false);
+    }
+
+    roles::sync_user(app, discord_id).await;
+
+    let Some(minecraft_uuid) = user.minecraft_uuid.filter(|_| !user.banned) else {
+        return Ok(true);
+    };
+
+    let profile = match minecraft_profile(app, minecraft_uuid).await {
+        Ok(profile) => profile,
+        Err(err) => {
+            warn!("Failed to resolve Minecraft account {} \n {}", minecraft_uuid, err);
+            return Ok(true);
+        },
+    };
+
+    if eligible {
+        info!("{} meets the guild rules again, whitelisting {}", discord_id, profile.minecraft_username);
+        minecraft::minecraft_whitelist(app, &profile.minecraft_username, user.is_admin).await;
+    } else {
+        info!("{} no longer meets the guild rules, removing {} from the whitelist", discord_id, profile.minecraft_username);
+        minecraft::minecraft_whitelist_remove(app, &profile.minecraft_username).await;
+    }
+
+    Ok(true)
+}
+
+/// Re-checks the linked users that are due, taking leavers off the whitelist and putting returning
+/// members back on. Returns how many users changed eligibility.
+pub async fn recheck_linked_users(app: &State<App>) -> Result<u64, ApiError> {
+    let due = query!(r#"SELECT discord_id, eligible FROM users
+                        WHERE minecraft_uuid IS NOT NULL
+                          AND (eligibility_checked_at IS NULL OR eligibility_checked_at < NOW() - MAKE_INTERVAL(hours => $1))
+                        ORDER BY eligibility_checked_at NULLS FIRST
+                        LIMIT $2"#, RECHECK_AFTER_HOURS, CHECK_BATCH_SIZE)
+        .fetch_all(&app.db)
+        .await?;
+
+    let mut changed = 0;
+
+    for user in due {
+        let eligible = match check_user(app, user.discord_id).await {
+            Ok(Some(eligibility)) => eligibility == Eligibility::Eligible,
+            // Nothing to check with, keep the previous verdict until the user logs in again.
+            Ok(None) => user.eligible,
+            Err(err) => {
+                // Users that can't be checked, e.g. with a session from before the member scope was requested, are
+                // tried again later like any other instead of taking up the front of every run.
+                warn!("Failed to check the guild membership of {} \n {}", user.discord_id, err);
+                mark_checked(app, user.discord_id).await?;
+                continue;
+            },
+        };
+
+        if apply_eligibility(app, user.discord_id, eligible).await? {
+            changed += 1;
+        }
+    }
+
+    Ok(changed)
+}
+
+pub async fn eligibility_task(app: App) {
+    tasks::run_every(app, ELIGIBILITY_CHECK_INTERVAL, "checking guild memberships", |app| Box::pin(recheck_linked_users(app)), |changed| {
+        if changed > 0 {
+            info!("Updated the whitelist eligibility of {} user(s)", changed);
+        }
+    }).await
+}
\ No newline at end of file
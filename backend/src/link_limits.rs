@@ -0,0 +1,165 @@
+use chrono::{DateTime, Duration, Utc};
+use rocket::State;
+use sqlx::{query, PgConnection};
+use std::env;
+use uuid::Uuid;
+
+use crate::app::App;
+use crate::errors::ApiError;
+
+/// The monthly cap is counted over a rolling window of this many days.
+const MONTH_DAYS: i64 = 30;
+
+/// How often users may change their linked Minecraft account themselves. Admins are never limited.
+#[derive(Clone, Copy)]
+pub struct LinkLimits {
+    /// Minimum time between two changes, zero disables the cooldown.
+    pub cooldown: Duration,
+    /// Changes allowed per rolling month, zero disables the cap.
+    pub monthly_cap: i64,
+}
+
+impl LinkLimits {
+    /// Reads `LINK_CHANGE_COOLDOWN_HOURS` (default 24) and `LINK_CHANGES_PER_MONTH` (default 3).
+    pub fn from_env() -> Self {
+        let cooldown_hours = env::var("LINK_CHANGE_COOLDOWN_HOURS").ok()
+            .and_then(|hours| hours.parse::<i64>().ok())
+            .unwrap_or(24)
+            .max(0);
+        let monthly_cap = env::var("LINK_CHANGES_PER_MONTH").ok()
+            .and_then(|cap| cap.parse::<i64>().ok())
+            .unwrap_or(3)
+            .max(0);
+
+        Self { cooldown: Duration::hours(cooldown_hours), monthly_cap }
+    }
+
+    /// When the next change is allowed given the counted changes, oldest first. `None` means right away.
+    fn next_change_at(&self, changes: &[DateTime<Utc>], now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let cooldown_until = changes.last()
+            .filter(|_| self.cooldown > Duration::zero())
+            .map(|last| *last + self.cooldown);
+
+        let window_start = now - Duration::days(MONTH_DAYS);
+        let in_window = changes.iter().filter(|changed_at| **changed_at > window_start).collect::<Vec<_>>();
+        // Once at the cap, the next change is allowed when enough of the changes have left the window.
+        let cap_until = match self.monthly_cap {
+            0 => None,
+            cap if (in_window.len() as i64) < cap => None,
+            cap => Some(*in_window[in_window.len() - cap as usize] + Duration::days(MONTH_DAYS)),
+        };
+
+        cooldown_until.max(cap_until).filter(|next| *next > now)
+    }
+}
+
+/// Fails with `ApiError::LinkChangeLimited` if the user changed their linked account too recently or too often.
+/// Meant to run in the transaction that records the change, with the user's row locked.
+pub async fn check(app: &State<App>, conn: &mut PgConnection, discord_id: i64) -> Result<(), ApiError> {
+    let changes = query!("SELECT changed_at FROM minecraft_link_changes
+                          WHERE discord_id = $1
+                          AND changed_at > NOW() - make_interval(days => $2)
+                          AND changed_at > COALESCE((SELECT link_limit_reset_at FROM users WHERE discord_id = $1), '-infinity')
+                          ORDER BY changed_at", discord_id, MONTH_DAYS.max(app.link_limits.cooldown.num_days() + 1) as i32)
+        .fetch_all(conn)
+        .await?
+        .into_iter()
+        .map(|change| change.changed_at)
+        .collect::<Vec<_>>();
+
+    match app.link_limits.next_change_at(&changes, Utc::now()) {
+        Some(next_change_at) => Err(ApiError::LinkChangeLimited(next_change_at)),
+        None => Ok(()),
+    }
+}
+
+/// Counts a change of the user's linked account towards their limits.
+pub async fn record_change(conn: &mut PgConnection, discord_id: i64, minecraft_uuid: Uuid) -> Result<(), ApiError> {
+    query!("INSERT INTO minecraft_link_changes (discord_id, minecraft_uuid) VALUES ($1, $2)", discord_id, minecraft_uuid)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Lets the user change their linked account again right away, changes so far stop counting.
+pub async fn reset(conn: &mut PgConnection, discord_id: i64) -> Result<(), ApiError> {
+    query!("UPDATE users SET link_limit_reset_at = NOW() WHERE discord_id = $1 RETURNING discord_id", discord_id)
+        .fetch_optional(conn)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(cooldown_hours: i64, monthly_cap: i64) -> LinkLimits {
+        LinkLimits { cooldown: Duration::hours(cooldown_hours), monthly_cap }
+    }
+
+    fn days_ago(now: DateTime<Utc>, days: &[i64]) -> Vec<DateTime<Utc>> {
+        days.iter().map(|days| now - Duration::days(*days)).collect()
+    }
+
+    #[test]
+    fn allows_changes_below_the_cap() {
+        let now = Utc::now();
+
+        assert_eq!(limits(0, 3).next_change_at(&[], now), None);
+        assert_eq!(limits(0, 3).next_change_at(&days_ago(now, &[20, 10]), now), None);
+    }
+
+    #[test]
+    fn waits_for_the_oldest_change_to_leave_the_window() {
+        let now = Utc::now();
+        let changes = days_ago(now, &[20, 10, 5]);
+
+        assert_eq!(limits(0, 3).next_change_at(&changes, now), Some(changes[0] + Duration::days(MONTH_DAYS)));
+    }
+
+    #[test]
+    fn waits_for_enough_changes_to_leave_the_window_above_the_cap() {
+        let now = Utc::now();
+        // Possible after the cap was lowered, two changes have to leave the window to get below a cap of 2.
+        let changes = days_ago(now, &[20, 10, 5]);
+
+        assert_eq!(limits(0, 2).next_change_at(&changes, now), Some(changes[1] + Duration::days(MONTH_DAYS)));
+    }
+
+    #[test]
+    fn ignores_changes_outside_the_window() {
+        let now = Utc::now();
+        let changes = days_ago(now, &[45, 31, 10, 5]);
+
+        assert_eq!(limits(0, 3).next_change_at(&changes, now), None);
+        assert_eq!(limits(0, 2).next_change_at(&changes, now), Some(changes[2] + Duration::days(MONTH_DAYS)));
+    }
+
+    #[test]
+    fn applies_the_cooldown_after_the_last_change() {
+        let now = Utc::now();
+        let recent = vec![now - Duration::hours(2)];
+
+        assert_eq!(limits(24, 3).next_change_at(&recent, now), Some(recent[0] + Duration::hours(24)));
+        assert_eq!(limits(1, 3).next_change_at(&recent, now), None);
+    }
+
+    #[test]
+    fn uses_the_later_of_cooldown_and_cap() {
+        let now = Utc::now();
+        let changes = vec![now - Duration::days(29) - Duration::hours(23), now - Duration::hours(2)];
+
+        assert_eq!(limits(24, 2).next_change_at(&changes, now), Some(changes[1] + Duration::hours(24)));
+    }
+
+    #[test]
+    fn zero_disables_the_limits() {
+        let now = Utc::now();
+        let changes = days_ago(now, &[3, 2, 1, 0]);
+
+        assert_eq!(limits(0, 0).next_change_at(&changes, now), None);
+    }
+}
\ No newline at end of file
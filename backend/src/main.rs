@@ -4,10 +4,11 @@ extern crate rocket;
 use chrono::serde::ts_seconds_option;
 use chrono::{DateTime, Utc};
 use std::env;
-use std::hash::{DefaultHasher, Hash, Hasher};
-use std::time::{Duration, Instant};
 use crate::app::App;
+use crate::audit::{Actor, AuditAction, AuditEvent};
+use crate::rate_limit::{Lookup, RateLimit, WhitelistChange};
 use crate::errors::ApiError;
+use crate::session_manager::StoredSession;
 use dotenvy::dotenv;
 use rocket::form::Form;
 use rocket::http::{CookieJar, Status};
@@ -19,13 +20,31 @@ use rocket::fairing::AdHoc;
 use rocket::fs::FileServer;
 use rocket_oauth2::{HyperRustlsAdapter, OAuth2, OAuthConfig, StaticProvider, TokenResponse};
 use serde::{Deserialize, Serialize};
-use sqlx::query;
+use serde_json::json;
+use std::net::IpAddr;
+use sqlx::{query, query_as};
 use uuid::Uuid;
 
 mod minecraft;
+mod name_history;
+mod admin;
 mod app;
+mod audit;
+mod bans;
+mod cache;
+mod command_queue;
+mod control;
+mod profiles;
+mod rate_limit;
+mod reconcile;
+mod roles;
+mod servers;
+mod status;
 mod errors;
+mod guild;
+mod link_limits;
 mod session_manager;
+mod tasks;
 
 struct Discord;
 
@@ -38,6 +57,10 @@ struct Whitelist {
 struct DiscordCallback {
     id: String,
     username: String,
+    global_name: Option<String>,
+    avatar: Option<String>,
+    /// "0" for accounts that moved to unique usernames.
+    discriminator: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -45,16 +68,18 @@ struct DiscordAccessTokenResponse {
     access_token: String,
     expires_in: i64,
     refresh_token: String,
+    #[serde(default)]
+    scope: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
-struct MinecraftUsernameToUuid {
+pub struct MinecraftUsernameToUuid {
     #[allow(dead_code)]
     name: String,
     id: Uuid,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct MinecraftUuidToUsernameProperties {
     name: String,
     value: String,
@@ -70,7 +95,10 @@ struct MinecraftUuidToUsername {
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct DiscordUserData {
-    pub discord_username: String
+    pub discord_username: String,
+    pub global_name: Option<String>,
+    pub avatar: Option<String>,
+    pub discriminator: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -101,11 +129,27 @@ pub struct Session {
     pub expired: bool,
 }
 
+pub struct AdminSession(pub Session);
+
 pub struct APIKey {}
 
 #[derive(Deserialize)]
 pub struct BanData {
-    pub uuid: Uuid
+    pub uuid: Uuid,
+    pub reason: Option<String>,
+    pub issuer: Option<String>,
+    #[serde(default, with = "ts_seconds_option")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub kick: bool,
+    #[serde(default)]
+    pub ingame: bool,
+}
+
+#[derive(Deserialize)]
+pub struct UnbanData {
+    pub uuid: Uuid,
+    pub issuer: Option<String>,
 }
 
 #[rocket::async_trait]
@@ -113,7 +157,9 @@ impl<'r> FromRequest<'r> for Session {
     type Error = String;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let app = request.rocket().state::<App>().unwrap();
+        let Some(app) = request.rocket().state::<App>() else {
+            return Outcome::Error((Status::InternalServerError, "App state is missing".to_string()));
+        };
 
         let session_cookie = request
             .cookies()
@@ -123,23 +169,38 @@ impl<'r> FromRequest<'r> for Session {
             Some(token) => token,
             None => {
                 return Outcome::Error((
-                    Status::BadRequest,
+                    Status::Unauthorized,
                     String::from("Session Id cookie is missing"),
                 ))
             }
         };
 
         if let Ok(session_id) = Uuid::parse_str(cookie.value()) {
-            let session = query!("SELECT * FROM sessions WHERE session_id = $1", session_id)
-                .fetch_optional(&app.db)
-                .await
-                .unwrap();
+            let session = match session_manager::resolve_session(<&State<App>>::from(app), session_id).await {
+                Ok(session) => session,
+                Err(err) => {
+                    error!("A unknown error occurred while loading a session \n {}", err);
+                    return Outcome::Error((Status::InternalServerError, "Failed to load the session".to_string()));
+                }
+            };
 
-            if let Some(session) = session {
-                let user = query!("SELECT * FROM users WHERE discord_id = $1", session.user_id)
-                    .fetch_optional(&app.db)
-                    .await
-                    .unwrap();
+            if let Some((session, rotated_cookie)) = session {
+                if let Some(rotated_cookie) = rotated_cookie {
+                    request.cookies().add_private(rotated_cookie);
+                }
+
+                let user_agent = request.headers().get_one("User-Agent");
+                if let Err(err) = session_manager::touch_session(<&State<App>>::from(app), session.session_id, user_agent, request.client_ip()).await {
+                    error!("A unknown error occurred while updating a session \n {}", err);
+                }
+
+                let user = match query!("SELECT * FROM users WHERE discord_id = $1", session.user_id).fetch_optional(&app.db).await {
+                    Ok(user) => user,
+                    Err(err) => {
+                        error!("A unknown error occurred while loading a session \n {}", err);
+                        return Outcome::Error((Status::InternalServerError, "Failed to load the session".to_string()));
+                    }
+                };
 
                 if let Some(user) = user {
                     return Outcome::Success(Session {
@@ -161,7 +222,22 @@ impl<'r> FromRequest<'r> for Session {
             }
         }
 
-        Outcome::Error((Status::BadRequest, "A error occurred with that request".to_string()))
+        Outcome::Error((Status::Unauthorized, "A error occurred with that request".to_string()))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminSession {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let session = rocket::outcome::try_outcome!(request.guard::<Session>().await);
+
+        if session.user.is_admin {
+            return Outcome::Success(AdminSession(session));
+        }
+
+        Outcome::Error((Status::Forbidden, "You're not an admin!".to_string()))
     }
 }
 
@@ -171,7 +247,7 @@ impl<'r> FromRequest<'r> for APIKey {
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
         if let Some(auth_key) = request.headers().get_one("Authorization") {
-            if auth_key == env::var("RAILWAYS_TWEAKS_API_KEY").unwrap() {
+            if env::var("RAILWAYS_TWEAKS_API_KEY").is_ok_and(|api_key| api_key == auth_key) {
                 return Outcome::Success(APIKey {});
             }
         }
@@ -187,19 +263,52 @@ async fn rocket() -> _ {
     let app: App = App::new().await;
 
     sqlx::migrate!().run(&app.db).await.expect("Failed to apply migrations :(");
+    servers::seed_default_server(&app.db).await.expect("Failed to register the default server");
+
+    let tasks_app = app.clone();
 
     let mut rocket = rocket::build()
         .manage(app)
+        .register("/backend/", catchers![errors::default_catcher])
         .mount("/backend/", routes![
             discord_login,
             discord_logout,
             discord_callback,
             minecraft_username_change,
             get_user_info,
+            list_user_sessions,
+            revoke_other_sessions,
+            revoke_user_session,
             username_to_uuid_minecraft,
             id_to_username_minecraft,
+            name_history_minecraft,
             id_to_username_discord,
-            minecraft_ban
+            minecraft_ban,
+            minecraft_unban,
+            server_status,
+            admin::admin_list_users,
+            admin::admin_get_user,
+            admin::admin_get_user_bans,
+            admin::admin_get_user_names,
+            admin::admin_ban_user,
+            admin::admin_unban_user,
+            admin::admin_unlink_user,
+            admin::admin_whitelist_user,
+            admin::admin_list_pending_commands,
+            admin::admin_retry_pending_command,
+            admin::admin_discard_pending_command,
+            admin::admin_whitelist_drift,
+            admin::admin_whitelist_reconcile,
+            admin::admin_list_servers,
+            admin::admin_get_server,
+            admin::admin_create_server,
+            admin::admin_update_server,
+            admin::admin_delete_server,
+            admin::admin_cache_stats,
+            admin::admin_metrics,
+            health,
+            admin::admin_list_audit_events,
+            admin::admin_reset_link_limits
         ])
         .attach(AdHoc::on_ignite("OAuth Config", |rocket| async {
             let config = OAuthConfig::new(
@@ -210,82 +319,143 @@ async fn rocket() -> _ {
             );
             
             rocket.attach(OAuth2::<Discord>::custom(HyperRustlsAdapter::default(), config))
-        }));
+        }))
+        .attach(rate_limit::retry_after_fairing())
+        .attach(AdHoc::on_liftoff("Background Tasks", |_| Box::pin(async move {
+            rocket::tokio::spawn(bans::expiry_task(tasks_app.clone()));
+            rocket::tokio::spawn(command_queue::retry_task(tasks_app.clone()));
+            rocket::tokio::spawn(profiles::refresh_task(tasks_app.clone()));
+            rocket::tokio::spawn(name_history::history_task(tasks_app.clone()));
+            rocket::tokio::spawn(guild::eligibility_task(tasks_app.clone()));
+            rocket::tokio::spawn(roles::retry_task(tasks_app.clone()));
+            rocket::tokio::spawn(session_manager::cleanup_task(tasks_app.clone()));
+            rocket::tokio::spawn(reconcile::reconcile_task(tasks_app));
+        })));
 
     if !cfg!(debug_assertions) {
         rocket = rocket.mount("/", FileServer::from("./static"));
+    } else {
+        rocket = rocket
+            .manage(audit::WebhookInbox::default())
+            .mount("/backend/", routes![audit::debug_webhook_receive, audit::debug_webhook_inbox]);
     }
 
     rocket
 }
 
 #[get("/login/discord")]
-async fn discord_login(app: &State<App>, oauth2: OAuth2<Discord>, cookies: &CookieJar<'_>) -> Redirect {
-    let session_cookie = cookies.get_private("session_id");
-
-    if let Some(cookie) = session_cookie {
-        if let Ok(session_id) = Uuid::parse_str(cookie.value()) {
-            let session = query!("SELECT * FROM sessions WHERE session_id = $1 AND expired = FALSE AND expires_at > NOW()", session_id)
-                .fetch_optional(&app.db)
-                .await
-                .unwrap();
-
-            if let Some(session) = session {
-                let req = app.https.post("https://discord.com/api/oauth2/token")
-                    .header("Content-Type", "application/x-www-form-urlencoded")
-                    .basic_auth(
-                        env::var("DISCORD_CLIENT_ID").expect("Missing client id"),
-                        Some(env::var("DISCORD_CLIENT_SECRET").expect("Missing client secret")),
-                    )
-                    .body(format!("grant_type=refresh_token&refresh_token={}", session.refresh_token))
-                    .send()
-                    .await
-                    .unwrap()
-                    .json::<DiscordAccessTokenResponse>()
-                    .await
-                    .unwrap();
-
-                query!("UPDATE sessions SET expired = true WHERE session_id = $1", session_id)
-                    .execute(&app.db)
-                    .await
-                    .unwrap();
-
-                let session_cookie = session_manager::generate_session(app, &req.access_token, &req.refresh_token, req.expires_in).await;
+async fn discord_login(app: &State<App>, oauth2: OAuth2<Discord>, cookies: &CookieJar<'_>) -> Result<Redirect, ApiError> {
+    let scopes = guild::required_scopes(app);
+
+    // A refresh keeps the scopes the session was granted, so sessions missing one go through a full login instead.
+    if let Some(session) = active_session(app, cookies).await?.filter(|session| session.has_scopes(scopes)) {
+        // Falls through to a fresh Discord login if the refresh token is no good anymore.
+        match session_manager::refresh_session(app, session.session_id, &session.refresh_token).await {
+            Ok(session_cookie) => {
                 cookies.add_private(session_cookie);
+                return Ok(Redirect::to("/"));
+            },
+            Err(err) => error!("A unknown error occurred while refreshing a Discord session \n {}", err),
+        }
+    }
 
-                return Redirect::to("/");
+    Ok(oauth2.get_redirect(cookies, scopes)?)
+}
+
+#[get("/logout/discord")]
+async fn discord_logout(app: &State<App>, cookies: &CookieJar<'_>, ip: Option<IpAddr>) -> Redirect {
+    // Logging out always clears the cookie, even if the session is already gone or can't be looked up.
+    match active_session(app, cookies).await {
+        Ok(Some(session)) => {
+            if let Err(err) = revoke_session(app, &session, &Actor::user(session.user_id, ip)).await {
+                error!("A unknown error occurred while expiring a session \n {}", err);
             }
-        }
+        },
+        Ok(None) => (),
+        Err(err) => error!("A unknown error occurred while loading a session \n {}", err),
+    }
+
+    cookies.remove_private("session_id");
+
+    Redirect::to("/")
+}
+
+/// The session behind the `session_id` cookie, unless it is missing, expired or logged out.
+async fn active_session(app: &State<App>, cookies: &CookieJar<'_>) -> Result<Option<StoredSession>, ApiError> {
+    let Some(session_id) = cookies.get_private("session_id").and_then(|cookie| Uuid::parse_str(cookie.value()).ok()) else {
+        return Ok(None);
     };
 
-    oauth2.get_redirect(cookies, &["identify"]).unwrap()
+    Ok(query_as!(StoredSession, "SELECT session_id, user_id, access_token, refresh_token, expires_at, expired, replaced_by, rotated_at, scope FROM sessions
+                                 WHERE session_id = $1 AND expired = FALSE AND expires_at > NOW()", session_id)
+        .fetch_optional(&app.db)
+        .await?)
 }
 
-#[get("/logout/discord")]
-async fn discord_logout(app: &State<App>, cookies: &CookieJar<'_>) -> Redirect {
-    let session_cookie = cookies.get_private("session_id");
+#[get("/users/@me/sessions")]
+async fn list_user_sessions(session_option: Option<Session>, app: &State<App>) -> Result<Json<Vec<session_manager::SessionInfo>>, ApiError> {
+    let session = session_option.ok_or(ApiError::NotLoggedIn)?;
 
-    if let Some(cookie) = session_cookie {
-        if let Ok(session_id) = Uuid::parse_str(cookie.value()) {
-            let session = query!("SELECT * FROM sessions WHERE session_id = $1 AND expired = FALSE AND expires_at > NOW()", session_id)
-                .fetch_optional(&app.db)
-                .await
-                .unwrap()
-                .unwrap();
+    Ok(Json(session_manager::list_sessions(app, session.user.discord_id, session.session_id).await?))
+}
+
+/// Logs out every other browser, keeping the one making the request.
+#[delete("/users/@me/sessions")]
+async fn revoke_other_sessions(session_option: Option<Session>, app: &State<App>, ip: Option<IpAddr>) -> Result<Json<Vec<session_manager::SessionInfo>>, ApiError> {
+    let session = session_option.ok_or(ApiError::NotLoggedIn)?;
+    let actor = Actor::user(session.user.discord_id, ip);
 
-            query!("UPDATE sessions SET expired = true WHERE session_id = $1", session_id)
-                .execute(&app.db)
-                .await
-                .unwrap();
+    for other in session_manager::get_other_sessions(app, session.user.discord_id, session.session_id).await? {
+        revoke_session(app, &other, &actor).await?;
+    }
+
+    Ok(Json(session_manager::list_sessions(app, session.user.discord_id, session.session_id).await?))
+}
+
+#[delete("/users/@me/sessions/<id>")]
+async fn revoke_user_session(session_option: Option<Session>, app: &State<App>, cookies: &CookieJar<'_>, ip: Option<IpAddr>, id: i32) -> Result<Json<Vec<session_manager::SessionInfo>>, ApiError> {
+    let session = session_option.ok_or(ApiError::NotLoggedIn)?;
+
+    let target = session_manager::get_user_session(app, session.user.discord_id, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
 
-            session_manager::revoke_discord_token(app, session.access_token).await;
-            session_manager::revoke_discord_token(app, session.refresh_token).await;
+    revoke_session(app, &target, &Actor::user(session.user.discord_id, ip)).await?;
 
-            cookies.remove_private("session_id");
+    if target.session_id == session.session_id {
+        cookies.remove_private("session_id");
+    }
+
+    Ok(Json(session_manager::list_sessions(app, session.user.discord_id, session.session_id).await?))
+}
+
+/// Expires the session and revokes its Discord tokens. Discord being unreachable doesn't keep the session alive.
+async fn revoke_session(app: &State<App>, session: &StoredSession, actor: &Actor) -> Result<(), ApiError> {
+    expire_session(app, session.session_id, actor).await?;
+
+    for token in [&session.access_token, &session.refresh_token] {
+        if let Err(err) = session_manager::revoke_discord_token(app, token).await {
+            error!("A unknown error occurred while revoking a Discord token \n {}", err);
         }
     }
 
-    Redirect::to("/")
+    Ok(())
+}
+
+async fn expire_session(app: &State<App>, session_id: Uuid, actor: &Actor) -> Result<(), ApiError> {
+    let mut tx = app.db.begin().await?;
+
+    let session = query!("UPDATE sessions SET expired = true WHERE session_id = $1 RETURNING user_id", session_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    audit::record(&mut tx, &AuditEvent::new(AuditAction::Logout, actor)
+        .user(session.user_id)
+        .snapshot(Some(json!({ "session_id": session_id })), None)).await?;
+
+    tx.commit().await?;
+
+    Ok(())
 }
 
 #[get("/auth/discord")]
@@ -298,29 +468,26 @@ async fn discord_callback(app: &State<App>, token: TokenResponse<Discord>, cooki
         .header("Authorization", format!("Bearer {}", token.access_token()))
         .send()
         .await?
+        .error_for_status()?
         .json::<DiscordCallback>()
         .await?;
 
-    let user_id = match user.id.parse::<i64>() {
-        Ok(i) => i,
-        Err(err) => return Err(ApiError::ParseStringAsIntError(err))
-    };
-
-    query!("INSERT INTO users (discord_id, discord_username)
-            VALUES ($1, $2)
-            ON CONFLICT (discord_id) DO NOTHING;", user_id, user.username)
-        .execute(&app.db)
-        .await?;
+    session_manager::upsert_user(app, &user).await?;
 
-    let session_cookie = session_manager::generate_session_with_callback(app, user, token.access_token(), token.refresh_token().unwrap(), secs).await;
+    let refresh_token = token.refresh_token().ok_or(ApiError::OptionError)?;
+    let session_cookie = session_manager::generate_session_with_callback(app, user, token.access_token(), refresh_token, secs, token.scope()).await?;
     cookies.add_private(session_cookie);
 
-    Ok(Redirect::to(env::var("BASE_URL").expect("BASE_URL is not set")))
+    Ok(Redirect::to(app.base_url.clone()))
 }
 
 #[post("/minecraft/username/change", data = "<whitelist_data>")]
-async fn minecraft_username_change(app: &State<App>, session_option: Option<Session>, whitelist_data: Form<Whitelist>) -> Result<(), ApiError> {
-    let session = session_option.ok_or_else(|| ApiError::OptionError)?;
+async fn minecraft_username_change(_rate_limit: RateLimit<WhitelistChange>, app: &State<App>, session_option: Option<Session>, ip: Option<IpAddr>, whitelist_data: Form<Whitelist>) -> Result<(), ApiError> {
+    let session = session_option.ok_or(ApiError::NotLoggedIn)?;
+
+    if !minecraft::is_valid_username(&whitelist_data.username) {
+        return Err(ApiError::BadRequest);
+    }
 
     let query_optional = query!("SELECT minecraft_uuid, banned FROM users WHERE discord_id = $1", &session.user.discord_id)
         .fetch_optional(&app.db)
@@ -330,173 +497,304 @@ async fn minecraft_username_change(app: &State<App>, session_option: Option<Sess
         if query.banned  {
             return Err(ApiError::BadRequest);
         }
-        
-        return match username_to_uuid_minecraft(app, Some(session.clone()), &whitelist_data.clone().username).await {
-            Ok(profile) => {
-                let result = query!("UPDATE users SET minecraft_uuid = $1 WHERE discord_id = $2", profile.id, session.user.discord_id)
-                    .execute(&app.db)
-                    .await;
-
-                match result {
-                    Ok(_) => {
-                        if let Some(uuid) = query.minecraft_uuid {
-                            let username = id_to_username_minecraft(app, Some(session.clone()), &uuid.to_string()).await;
-                            if let Ok(json) = username {
-                                minecraft::minecraft_whitelist_remove(app, &json.minecraft_username).await;
-                            }
-                        }
-
-                        minecraft::minecraft_whitelist(app, &whitelist_data).await;
-
-                        Ok(())
-                    },
-                    Err(sqlx::Error::Database(err)) if err.constraint() == Some("unique_minecraft_uuid") => {
-                        return Err(ApiError::CollisionError);
-                    },
-                    Err(_) => {
-                        return Err(ApiError::BadRequest);
-                    }
-                }
-            },
-            Err(err) => Err(err)
+
+        let eligibility = guild::check_for_link(app, session.user.discord_id, &session.access_token).await?;
+        guild::apply_eligibility(app, session.user.discord_id, eligibility == guild::Eligibility::Eligible).await?;
+
+        match eligibility {
+            guild::Eligibility::Eligible => (),
+            guild::Eligibility::NotMember => return Err(ApiError::NotEligible("You need to join our Discord server to get whitelisted!".to_string())),
+            guild::Eligibility::MissingRole => return Err(ApiError::NotEligible("You're missing the Discord role needed to get whitelisted!".to_string())),
         }
+
+        let actor = Actor::user(session.user.discord_id, ip);
+        let limited = !session.user.is_admin;
+        link_minecraft_account(app, &actor, session.user.discord_id, query.minecraft_uuid, &whitelist_data.username, limited).await?;
+
+        return Ok(());
     }
 
     Err(ApiError::BadRequest)
 }
 
+/// Points `discord_id` at the Minecraft account `username` and moves the server whitelist entry
+/// from the previously linked account (if any) over to the new one. With `limited`, changing to a different
+/// account is subject to and counted towards the user's link limits. Returns the UUID of the linked account.
+pub async fn link_minecraft_account(app: &State<App>, actor: &Actor, discord_id: i64, previous_uuid: Option<Uuid>, username: &str, limited: bool) -> Result<Uuid, ApiError> {
+    let profile = minecraft_uuid(app, username).await?;
+    let previous_name = match previous_uuid {
+        Some(uuid) => minecraft_profile(app, uuid).await.ok().map(|profile| profile.minecraft_username),
+        None => None,
+    };
+
+    let action = if previous_uuid.is_some() { AuditAction::Relink } else { AuditAction::Link };
+    let event = AuditEvent::new(action, actor)
+        .user(discord_id)
+        .minecraft_name(Some(profile.name.clone()))
+        .change(previous_name.clone(), Some(profile.name.clone()))
+        .snapshot(
+            previous_uuid.map(|uuid| json!({ "minecraft_uuid": uuid, "minecraft_name": previous_name })),
+            Some(json!({ "minecraft_uuid": profile.id, "minecraft_name": profile.name })),
+        );
+
+    let mut tx = app.db.begin().await?;
+
+    // Only changes count, linking an account for the first time is always allowed.
+    let counted = limited && previous_uuid.is_some_and(|previous_uuid| previous_uuid != profile.id);
+    if counted {
+        // Serializes concurrent changes of the same user, so they can't both pass the check.
+        query!("SELECT discord_id FROM users WHERE discord_id = $1 FOR UPDATE", discord_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        link_limits::check(app, &mut tx, discord_id).await?;
+    }
+
+    let result = query!("UPDATE users SET minecraft_uuid = $1 WHERE discord_id = $2 RETURNING is_admin", profile.id, discord_id)
+        .fetch_one(&mut *tx)
+        .await;
+
+    let user = match result {
+        Ok(user) => user,
+        Err(sqlx::Error::Database(err)) if err.constraint() == Some("unique_minecraft_uuid") => {
+            return Err(ApiError::CollisionError);
+        },
+        Err(_) => {
+            return Err(ApiError::BadRequest);
+        }
+    };
+
+    if counted {
+        link_limits::record_change(&mut tx, discord_id, profile.id).await?;
+    }
+
+    audit::record(&mut tx, &event).await?;
+    tx.commit().await?;
+
+    if let Some(previous_name) = &previous_name {
+        minecraft::minecraft_whitelist_remove(app, previous_name).await;
+    }
+
+    if let Err(err) = name_history::record_name(app, profile.id, &profile.name).await {
+        error!("A unknown error occurred while recording a Minecraft name \n {}", err);
+    }
+
+    minecraft::minecraft_whitelist(app, &profile.name, user.is_admin).await;
+    roles::sync_user(app, discord_id).await;
+    audit::notify(app, &event);
+
+    Ok(profile.id)
+}
+
+/// Liveness and database check for uptime monitors, the details are at `/admin/metrics`.
+#[get("/health")]
+async fn health(app: &State<App>) -> (Status, Json<serde_json::Value>) {
+    match query!("SELECT 1 AS ok").fetch_one(&app.db).await {
+        Ok(_) => (Status::Ok, Json(json!({ "status": "ok", "database": true }))),
+        Err(err) => {
+            error!("A unknown error occurred while checking the database \n {}", err);
+            (Status::ServiceUnavailable, Json(json!({ "status": "unavailable", "database": false })))
+        },
+    }
+}
+
 #[get("/users/@me")]
 async fn get_user_info(session_option: Option<Session>) -> Result<Json<User>, ApiError> {
-    let session = session_option.ok_or_else(|| ApiError::OptionError)?;
+    let session = session_option.ok_or(ApiError::NotLoggedIn)?;
     Ok(Json(session.user))
 }
 
 #[get("/users/username_to_uuid/minecraft/<username>")]
-async fn username_to_uuid_minecraft(app: &State<App>, session_option: Option<Session>, username: &str) -> Result<Json<MinecraftUsernameToUuid>, ApiError> {
-    let session = session_option.ok_or_else(|| ApiError::OptionError)?;
-
-    let mut hasher = DefaultHasher::new();
-    session.hash(&mut hasher);
-    username.hash(&mut hasher);
-    let cache_key = ("username_to_uuid_minecraft", hasher.finish());
-    let cache_duration = Duration::new(3600, 0);
-
-    {
-        let mut cache = app.cache.write().unwrap();
-        cache.retain(|_, (_, timestamp)| timestamp.elapsed() < cache_duration);
-        if let Some((data, timestamp)) = cache.get(&cache_key) {
-            if timestamp.elapsed() < cache_duration {
-                let deserialized: MinecraftUsernameToUuid = serde_json::from_str(&data).unwrap();
-                return Ok(Json(deserialized.clone()));
-            }
-        }
+async fn username_to_uuid_minecraft(_rate_limit: RateLimit<Lookup>, app: &State<App>, session_option: Option<Session>, username: &str) -> Result<Json<MinecraftUsernameToUuid>, ApiError> {
+    session_option.ok_or(ApiError::NotLoggedIn)?;
+
+    Ok(Json(minecraft_uuid(app, username).await?))
+}
+
+/// Resolves a Minecraft username to its account, going through the shared lookup cache and then the
+/// stored profiles before asking Mojang.
+pub async fn minecraft_uuid(app: &State<App>, username: &str) -> Result<MinecraftUsernameToUuid, ApiError> {
+    if !minecraft::is_valid_username(username) {
+        return Err(ApiError::BadRequest);
     }
 
-    let request = app.https.get(format!("https://api.minecraftservices.com/minecraft/profile/lookup/name/{}", username))
-        .send()
-        .await;
+    app.cache.username_to_uuid_minecraft.get_or_try_insert_optional_with(username.to_lowercase(), || async {
+        if let Some(profile) = profiles::get_by_name(app, username).await? {
+            return Ok(Some(profile));
+        }
 
-    if let Ok(req) = request {
-        if let Ok(user_profile) = req
-            .json::<MinecraftUsernameToUuid>()
-            .await {
-            {
-                let mut write_cache = app.cache.write().unwrap();
-                write_cache.insert(cache_key, (serde_json::to_string(&user_profile).unwrap(), Instant::now()));
-            }
+        let response = app.https.get(format!("https://api.minecraftservices.com/minecraft/profile/lookup/name/{}", username))
+            .send()
+            .await?;
 
-            return Ok(Json(user_profile));
-        };
-    }
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
 
-    Err(ApiError::OptionError)
+        let profile = response.error_for_status()?.json::<MinecraftUsernameToUuid>().await?;
+        if let Err(err) = profiles::store_name(app, &profile).await {
+            error!("A unknown error occurred while storing a Minecraft profile \n {}", err);
+        }
+
+        Ok::<_, ApiError>(Some(profile))
+    }).await?
+        .ok_or(ApiError::NotFound)
 }
 
 #[get("/users/id_to_username/minecraft/<uuid>")]
-async fn id_to_username_minecraft(app: &State<App>, session_option: Option<Session>, uuid: &str) -> Result<Json<MinecraftUserData>, ApiError> {
-    let session = session_option.ok_or_else(|| ApiError::OptionError)?;
-
-    let mut hasher = DefaultHasher::new();
-    session.hash(&mut hasher);
-    uuid.hash(&mut hasher);
-    let cache_key = ("id_to_username_minecraft", hasher.finish());
-    let cache_duration = Duration::new(3600, 0);
-
-    {
-        let mut cache = app.cache.write().unwrap();
-        cache.retain(|_, (_, timestamp)| timestamp.elapsed() < cache_duration);
-        if let Some((data, timestamp)) = cache.get(&cache_key) {
-            if timestamp.elapsed() < cache_duration {
-                let deserialized: MinecraftUserData = serde_json::from_str(&data).unwrap();
-                return Ok(Json(deserialized.clone()));
-            }
+async fn id_to_username_minecraft(_rate_limit: RateLimit<Lookup>, app: &State<App>, session_option: Option<Session>, uuid: &str) -> Result<Json<MinecraftUserData>, ApiError> {
+    session_option.ok_or(ApiError::NotLoggedIn)?;
+    let uuid = Uuid::parse_str(uuid).map_err(|_| ApiError::BadRequest)?;
+
+    Ok(Json(minecraft_profile(app, uuid).await?))
+}
+
+/// Names the account has gone by since it was first linked, most recent first.
+#[get("/users/name_history/minecraft/<uuid>")]
+async fn name_history_minecraft(_rate_limit: RateLimit<Lookup>, app: &State<App>, session_option: Option<Session>, uuid: &str) -> Result<Json<Vec<name_history::NameHistoryEntry>>, ApiError> {
+    session_option.ok_or(ApiError::NotLoggedIn)?;
+    let uuid = Uuid::parse_str(uuid).map_err(|_| ApiError::BadRequest)?;
+
+    Ok(Json(name_history::get_history(app, uuid).await?))
+}
+
+/// Resolves a Minecraft UUID to its current profile, going through the shared lookup cache and then the
+/// stored profiles before asking Mojang.
+pub async fn minecraft_profile(app: &State<App>, uuid: Uuid) -> Result<MinecraftUserData, ApiError> {
+    app.cache.id_to_username_minecraft.get_or_try_insert_optional_with(uuid, || async {
+        if let Some(profile) = profiles::get_by_uuid(app, uuid).await? {
+            return Ok(Some(profile));
         }
-    }
 
-    let mc_profile = app.https.get(format!("https://sessionserver.mojang.com/session/minecraft/profile/{}", uuid))
+        match fetch_minecraft_profile(app, &uuid.to_string()).await {
+            Ok(profile) => {
+                if let Err(err) = profiles::store_profile(app, uuid, &profile).await {
+                    error!("A unknown error occurred while storing a Minecraft profile \n {}", err);
+                }
+
+                Ok(Some(profile))
+            },
+            Err(ApiError::NotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }).await?
+        .ok_or(ApiError::NotFound)
+}
+
+/// Resolves a Minecraft UUID to its current profile through the Mojang session server, bypassing the cache.
+pub async fn fetch_minecraft_profile(app: &State<App>, uuid: &str) -> Result<MinecraftUserData, ApiError> {
+    let response = app.https.get(format!("https://sessionserver.mojang.com/session/minecraft/profile/{}", uuid))
         .send()
-        .await?
+        .await?;
+
+    // The session server answers unknown UUIDs with an empty 204 rather than a 404.
+    if matches!(response.status(), reqwest::StatusCode::NO_CONTENT | reqwest::StatusCode::NOT_FOUND) {
+        return Err(ApiError::NotFound);
+    }
+
+    let mc_profile = response
+        .error_for_status()?
         .json::<MinecraftUuidToUsername>()
         .await?;
 
-    let data = MinecraftUserData {
+    Ok(MinecraftUserData {
         minecraft_username: mc_profile.name,
         properties: mc_profile.properties,
-    };
-
-    {
-        let mut write_cache = app.cache.write().unwrap();
-        write_cache.insert(cache_key, (serde_json::to_string(&data).unwrap(), Instant::now()));
-    }
-
-    Ok(Json(data))
+    })
 }
 
 #[get("/users/id_to_username/discord/<id>")]
-async fn id_to_username_discord(app: &State<App>, session_option: Option<Session>, id: &str) -> Result<Json<DiscordUserData>, ApiError> {
-    let session = session_option.ok_or_else(|| ApiError::OptionError)?;
-
-    let mut hasher = DefaultHasher::new();
-    session.hash(&mut hasher);
-    id.hash(&mut hasher);
-    let cache_key = ("id_to_username_discord", hasher.finish());
-    let cache_duration = Duration::new(3600, 0);
-
-    {
-        let mut cache = app.cache.write().unwrap();
-        cache.retain(|_, (_, timestamp)| timestamp.elapsed() < cache_duration);
-        if let Some((data, timestamp)) = cache.get(&cache_key) {
-            if timestamp.elapsed() < cache_duration {
-                let deserialized: DiscordUserData = serde_json::from_str(&data).unwrap();
-                return Ok(Json(deserialized.clone()));
-            }
-        }
-    }
+async fn id_to_username_discord(_rate_limit: RateLimit<Lookup>, app: &State<App>, session_option: Option<Session>, id: i64) -> Result<Json<DiscordUserData>, ApiError> {
+    session_option.ok_or(ApiError::NotLoggedIn)?;
 
-    let discord_user = app.https.get(format!("https://discord.com/api/users/{}", session.user.discord_id))
-        .send()
-        .await?
-        .json::<DiscordCallback>()
-        .await?;
+    let lookup = match &app.discord_bot {
+        Some(bot) => app.cache.id_to_username_discord.get_or_try_insert_optional_with(id, || async {
+            let response = bot.get(format!("https://discord.com/api/users/{}", id))
+                .send()
+                .await?;
+
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
 
-    let data = DiscordUserData {
-        discord_username: discord_user.username
+            let discord_user = response
+                .error_for_status()?
+                .json::<DiscordCallback>()
+                .await?;
+
+            Ok::<_, ApiError>(Some(DiscordUserData {
+                discord_username: discord_user.username,
+                global_name: discord_user.global_name,
+                avatar: discord_user.avatar,
+                discriminator: discord_user.discriminator,
+            }))
+        }).await,
+        None => Err(ApiError::NotFound),
     };
 
-    {
-        let mut write_cache = app.cache.write().unwrap();
-        write_cache.insert(cache_key, (serde_json::to_string(&data).unwrap(), Instant::now()));
+    match lookup {
+        Ok(Some(data)) => Ok(Json(data)),
+        Ok(None) => Err(ApiError::NotFound),
+        Err(err) => {
+            // Discord is unreachable or no bot token is configured, the name we stored is better than nothing.
+            let user = query!("SELECT discord_username, discord_global_name, discord_avatar FROM users WHERE discord_id = $1", id)
+                .fetch_optional(&app.db)
+                .await?
+                .ok_or(err)?;
+
+            Ok(Json(DiscordUserData {
+                discord_username: user.discord_username,
+                global_name: user.discord_global_name,
+                avatar: user.discord_avatar,
+                discriminator: None,
+            }))
+        },
     }
+}
+
+#[get("/server/status?<server>")]
+async fn server_status(_rate_limit: RateLimit<Lookup>, app: &State<App>, server: Option<&str>) -> Result<Json<status::ServerStatus>, ApiError> {
+    let data = app.cache.server_status.get_or_try_insert_with(server.map(str::to_string), || async {
+        let minecraft_server = servers::get_status_server(app, server).await?;
+        let address = minecraft_server.address.ok_or(ApiError::NotFound)?;
+
+        Ok::<_, ApiError>(status::ping(&minecraft_server.name, &address).await)
+    }).await?;
 
     Ok(Json(data))
 }
 
 #[post("/minecraft/ban", data = "<ban_data>")]
-async fn minecraft_ban(app: &State<App>, api_key: Option<APIKey>, ban_data: Json<BanData>) -> Result<Status, ApiError> {
+async fn minecraft_ban(app: &State<App>, api_key: Option<APIKey>, ip: Option<IpAddr>, ban_data: Json<BanData>) -> Result<Json<bans::BanOutcome>, ApiError> {
     api_key.ok_or_else(|| ApiError::Unauthorized)?;
-    query!("UPDATE users SET banned = true WHERE minecraft_uuid = $1", ban_data.uuid)
+
+    let user = query!("SELECT discord_id FROM users WHERE minecraft_uuid = $1", ban_data.uuid)
         .fetch_optional(&app.db)
-        .await?;
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    let ban_data = ban_data.into_inner();
+    let actor = Actor::new(ban_data.issuer.unwrap_or_else(|| "api".to_string()), ip);
+    let options = bans::BanOptions {
+        reason: ban_data.reason,
+        expires_at: ban_data.expires_at,
+        kick: ban_data.kick,
+        ingame: ban_data.ingame,
+    };
 
-    Ok(Status::Ok)
+    Ok(Json(bans::ban_user(app, user.discord_id, &actor, options).await?))
 }
+
+#[post("/minecraft/unban", data = "<unban_data>")]
+async fn minecraft_unban(app: &State<App>, api_key: Option<APIKey>, ip: Option<IpAddr>, unban_data: Json<UnbanData>) -> Result<Json<bans::UnbanOutcome>, ApiError> {
+    api_key.ok_or_else(|| ApiError::Unauthorized)?;
+
+    let user = query!("SELECT discord_id FROM users WHERE minecraft_uuid = $1", unban_data.uuid)
+        .fetch_optional(&app.db)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    let actor = Actor::new(unban_data.issuer.as_deref().unwrap_or("api"), ip);
+
+    Ok(Json(bans::unban_user(app, user.discord_id, &actor).await?))
+}
\ No newline at end of file
@@ -1,36 +1,196 @@
-use rocket::form::Form;
-use std::env;
-
 use rocket::State;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 use crate::app::App;
-use crate::Whitelist;
+use crate::command_queue;
+use crate::control;
+use crate::errors::ApiError;
+use crate::servers::{self, MinecraftServer};
+
+#[derive(Serialize, Clone)]
+pub struct CommandResult {
+    /// Name of the server the command was sent to, if it got that far.
+    pub server: Option<String>,
+    pub command: String,
+    pub success: bool,
+    pub error: Option<String>,
+    /// Whether a failed command was queued to be retried in the background.
+    pub queued: bool,
+}
+
+impl CommandResult {
+    pub fn failed(command: String, error: String) -> Self {
+        Self { server: None, command, success: false, error: Some(error), queued: false }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WhitelistEntry {
+    pub uuid: Uuid,
+    pub name: String,
+}
+
+/// A server's whitelist as read through its control backend.
+pub struct Whitelist {
+    pub entries: Vec<WhitelistEntry>,
+    /// Whitelisted names that couldn't be resolved to a Minecraft account, only backends that list names have these.
+    pub unresolved: Vec<String>,
+}
+
+/// Whether `username` is a valid Minecraft name: 1 to 16 letters, digits or underscores. Anything else must never
+/// reach a lookup URL or a console command.
+pub fn is_valid_username(username: &str) -> bool {
+    (1..=16).contains(&username.len()) && username.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'_')
+}
+
+/// Longest ban reason accepted, kick and ban messages longer than this are unreadable in game anyway.
+pub const MAX_REASON_LENGTH: usize = 256;
+
+/// Whether `reason` can be used as a kick or ban message: at most `MAX_REASON_LENGTH` characters and no control
+/// characters, a line break would end the console command and start a new one.
+pub fn is_valid_reason(reason: &str) -> bool {
+    reason.chars().count() <= MAX_REASON_LENGTH && !reason.chars().any(char::is_control)
+}
 
-pub async fn minecraft_whitelist(app: &State<App>, whitelist_data: &Form<Whitelist>) {
-    run_command(
+/// Makes `reason` safe to append to a console command, in case it didn't go through `is_valid_reason`.
+fn console_reason(reason: &str) -> String {
+    reason.chars()
+        .map(|char| if char.is_control() { ' ' } else { char })
+        .take(MAX_REASON_LENGTH)
+        .collect()
+}
+
+/// Whitelists `username` on every enabled server whose whitelist policy admits the user.
+pub async fn minecraft_whitelist(app: &State<App>, username: &str, is_admin: bool) -> Vec<CommandResult> {
+    let servers = match servers::get_enabled_servers(app).await {
+        Ok(servers) => servers,
+        Err(err) => return vec![CommandResult::failed(format!("whitelist add {}", username), err.to_string())],
+    };
+
+    let mut results = Vec::new();
+
+    for server in servers.iter().filter(|server| server.allows(is_admin)) {
+        results.push(minecraft_whitelist_on(app, server, username).await);
+    }
+
+    results
+}
+
+pub async fn minecraft_whitelist_on(app: &State<App>, server: &MinecraftServer, username: &str) -> CommandResult {
+    run_queued_command(
         app,
-        format!("whitelist add {}", whitelist_data.username),
-        format!("A unknown error occurred while whitelisting user {}", whitelist_data.username),
+        server,
+        username,
+        format!("whitelist add {}", username),
+        format!("A unknown error occurred while whitelisting user {} on {}", username, server.name),
     ).await
 }
 
-pub async fn minecraft_whitelist_remove(app: &State<App>, username: &str) {
-    run_command(
+/// Un-whitelists `username` on every enabled server, regardless of its whitelist policy.
+pub async fn minecraft_whitelist_remove(app: &State<App>, username: &str) -> Vec<CommandResult> {
+    let servers = match servers::get_enabled_servers(app).await {
+        Ok(servers) => servers,
+        Err(err) => return vec![CommandResult::failed(format!("whitelist remove {}", username), err.to_string())],
+    };
+
+    let mut results = Vec::new();
+
+    for server in &servers {
+        results.push(minecraft_whitelist_remove_on(app, server, username).await);
+    }
+
+    results
+}
+
+pub async fn minecraft_whitelist_remove_on(app: &State<App>, server: &MinecraftServer, username: &str) -> CommandResult {
+    run_queued_command(
         app,
+        server,
+        username,
         format!("whitelist remove {}", username),
-        format!("A unknown error occurred while un-whitelisting user {}", username),
+        format!("A unknown error occurred while un-whitelisting user {} on {}", username, server.name),
     ).await
 }
 
-async fn run_command(app: &State<App>, command: String, error_message: String) {
-    let server_id = env::var("PTERODACTYL_SERVER_ID").expect("Missing Required Env Var PTERODACTYL_SERVER_ID");
+pub async fn minecraft_kick(app: &State<App>, username: &str, reason: &str) -> Vec<CommandResult> {
+    run_command_everywhere(
+        app,
+        format!("kick {} {}", username, console_reason(reason)),
+        format!("A unknown error occurred while kicking user {}", username),
+    ).await
+}
+
+pub async fn minecraft_ban(app: &State<App>, username: &str, reason: &str) -> Vec<CommandResult> {
+    run_command_everywhere(
+        app,
+        format!("ban {} {}", username, console_reason(reason)),
+        format!("A unknown error occurred while banning user {}", username),
+    ).await
+}
 
-    let response = app.pterodactyl.get_server(server_id)
+pub async fn minecraft_pardon(app: &State<App>, username: &str) -> Vec<CommandResult> {
+    run_command_everywhere(
+        app,
+        format!("pardon {}", username),
+        format!("A unknown error occurred while pardoning user {}", username),
+    ).await
+}
+
+pub async fn read_whitelist(app: &State<App>, server: &MinecraftServer) -> Result<Whitelist, ApiError> {
+    control::for_server(app, server)?
+        .read_whitelist()
+        .await
+}
+
+pub async fn send_command(app: &State<App>, server: &MinecraftServer, command: &str) -> Result<String, ApiError> {
+    control::for_server(app, server)?
         .send_command(command)
-        .await;
+        .await
+}
 
-    match response {
-        Ok(_) => (),
-        Err(err) => { error!("{} \n {}", error_message, err) }
+async fn run_command(app: &State<App>, server: &MinecraftServer, command: String, error_message: String) -> CommandResult {
+    let server_name = Some(server.name.clone());
+
+    match send_command(app, server, &command).await {
+        Ok(_) => CommandResult { server: server_name, command, success: true, error: None, queued: false },
+        Err(err) => {
+            error!("{} \n {}", error_message, err);
+            CommandResult { server: server_name, command, success: false, error: Some(err.to_string()), queued: false }
+        },
+    }
+}
+
+async fn run_command_everywhere(app: &State<App>, command: String, error_message: String) -> Vec<CommandResult> {
+    let servers = match servers::get_enabled_servers(app).await {
+        Ok(servers) => servers,
+        Err(err) => return vec![CommandResult::failed(command, err.to_string())],
+    };
+
+    let mut results = Vec::new();
+
+    for server in &servers {
+        results.push(run_command(app, server, command.clone(), format!("{} on {}", error_message, server.name)).await);
+    }
+
+    results
+}
+
+/// Like `run_command`, but a failure is queued for retry instead of being dropped. Older queued
+/// commands for the same player are superseded first so they can't be replayed out of order.
+async fn run_queued_command(app: &State<App>, server: &MinecraftServer, username: &str, command: String, error_message: String) -> CommandResult {
+    if let Err(err) = command_queue::supersede(app, server.id, username).await {
+        error!("A unknown error occurred while superseding queued commands for user {} \n {}", username, err);
     }
+
+    let mut result = run_command(app, server, command, error_message).await;
+
+    if !result.success {
+        match command_queue::enqueue(app, server.id, username, &result.command, result.error.as_deref()).await {
+            Ok(_) => result.queued = true,
+            Err(err) => error!("A unknown error occurred while queueing command {} \n {}", result.command, err),
+        }
+    }
+
+    result
 }
\ No newline at end of file
@@ -0,0 +1,122 @@
+use chrono::serde::ts_seconds;
+use chrono::{DateTime, Utc};
+use rocket::State;
+use serde::Serialize;
+use sqlx::{query, query_as};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::app::App;
+use crate::errors::ApiError;
+use crate::{fetch_minecraft_profile, profiles, tasks};
+
+const HISTORY_CHECK_INTERVAL: Duration = Duration::from_secs(600);
+/// Each linked account is resolved again once its name hasn't been confirmed for this long.
+const RECHECK_AFTER_HOURS: i32 = 24;
+
+#[derive(Serialize)]
+pub struct NameHistoryEntry {
+    pub name: String,
+    #[serde(with = "ts_seconds")]
+    pub first_seen_at: DateTime<Utc>,
+    #[serde(with = "ts_seconds")]
+    pub last_seen_at: DateTime<Utc>,
+}
+
+/// Names an account has been seen with, most recent first.
+pub async fn get_history(app: &State<App>, uuid: Uuid) -> Result<Vec<NameHistoryEntry>, ApiError> {
+    Ok(query_as!(NameHistoryEntry, "SELECT name, first_seen_at, last_seen_at FROM minecraft_name_history
+                                    WHERE minecraft_uuid = $1
+                                    ORDER BY last_seen_at DESC", uuid)
+        .fetch_all(&app.db)
+        .await?)
+}
+
+/// Records that `uuid` currently goes by `name`. Returns the previous name if the account was renamed.
+pub async fn record_name(app: &State<App>, uuid: Uuid, name: &str) -> Result<Option<String>, ApiError> {
+    let mut tx = app.db.begin().await?;
+
+    let latest = query!("SELECT id, name FROM minecraft_name_history
+                         WHERE minecraft_uuid = $1
+                         ORDER BY last_seen_at DESC
+                         LIMIT 1
+                         FOR UPDATE", uuid)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+    let renamed_from = match latest {
+        Some(latest) if latest.name == name => {
+            query!("UPDATE minecraft_name_history SET last_seen_at = NOW() WHERE id = $1", latest.id)
+                .execute(&mut *tx)
+                .await?;
+
+            None
+        },
+        latest => {
+            query!("INSERT INTO minecraft_name_history (minecraft_uuid, name) VALUES ($1, $2)", uuid, name)
+                .execute(&mut *tx)
+                .await?;
+
+            latest.map(|latest| latest.name)
+        },
+    };
+
+    tx.commit().await?;
+
+    Ok(renamed_from)
+}
+
+/// Resolves the linked accounts that are due for a check and records any renames.
+pub async fn check_linked_names(app: &State<App>) -> Result<u64, ApiError> {
+    let due = query!(r#"SELECT minecraft_uuid AS "uuid!" FROM users
+                        WHERE minecraft_uuid IS NOT NULL
+                          AND (name_checked_at IS NULL OR name_checked_at < NOW() - MAKE_INTERVAL(hours => $1))
+                        ORDER BY name_checked_at NULLS FIRST
+                        LIMIT $2"#, RECHECK_AFTER_HOURS, profiles::MOJANG_BATCH_SIZE)
+        .fetch_all(&app.db)
+        .await?;
+
+    let mut renamed = 0;
+
+    for row in due {
+        let profile = match fetch_minecraft_profile(app, &row.uuid.to_string()).await {
+            Ok(profile) => profile,
+            Err(ApiError::NotFound) => {
+                // Deleted or migrated accounts are checked again later like any other, not at the front of every run.
+                mark_checked(app, row.uuid).await?;
+                continue;
+            },
+            Err(err) => {
+                warn!("Failed to resolve Minecraft account {} \n {}", row.uuid, err);
+                break;
+            },
+        };
+
+        if let Some(previous) = record_name(app, row.uuid, &profile.minecraft_username).await? {
+            info!("Minecraft account {} was renamed from {} to {}", row.uuid, previous, profile.minecraft_username);
+            renamed += 1;
+        }
+
+        profiles::store_profile(app, row.uuid, &profile).await?;
+        app.cache.id_to_username_minecraft.insert(row.uuid, Some(profile));
+        mark_checked(app, row.uuid).await?;
+    }
+
+    Ok(renamed)
+}
+
+async fn mark_checked(app: &State<App>, uuid: Uuid) -> Result<(), ApiError> {
+    query!("UPDATE users SET name_checked_at = NOW() WHERE minecraft_uuid = $1", uuid)
+        .execute(&app.db)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn history_task(app: App) {
+    tasks::run_every(app, HISTORY_CHECK_INTERVAL, "checking Minecraft name history", |app| Box::pin(check_linked_names(app)), |renamed| {
+        if renamed > 0 {
+            info!("Recorded {} Minecraft account rename(s)", renamed);
+        }
+    }).await
+}
\ No newline at end of file
@@ -0,0 +1,117 @@
+use rocket::State;
+use sqlx::query;
+use sqlx::types::Json;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::app::App;
+use crate::errors::ApiError;
+use crate::tasks;
+use crate::{fetch_minecraft_profile, MinecraftUserData, MinecraftUsernameToUuid, MinecraftUuidToUsernameProperties};
+
+const REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(600);
+/// Profiles older than this are still served, but get refreshed in the background.
+const STALE_AFTER_HOURS: i32 = 24;
+/// Profiles older than this are no longer served and get fetched from Mojang again on the next lookup.
+const MAX_AGE_DAYS: i32 = 7;
+/// How many accounts a background run resolves through Mojang at most, which keeps each run well below Mojang's
+/// rate limits. A failed lookup ends the run early since it's most likely rate limited, the accounts left over
+/// are picked up by the next run.
+pub const MOJANG_BATCH_SIZE: i64 = 50;
+
+/// Looks up a stored profile by UUID. Rows only known from a username lookup have no properties yet and are skipped.
+pub async fn get_by_uuid(app: &State<App>, uuid: Uuid) -> Result<Option<MinecraftUserData>, ApiError> {
+    let profile = query!(r#"SELECT name, properties AS "properties!: Json<Vec<MinecraftUuidToUsernameProperties>>"
+                            FROM minecraft_profiles
+                            WHERE uuid = $1 AND properties IS NOT NULL AND fetched_at > NOW() - MAKE_INTERVAL(days => $2)"#,
+        uuid, MAX_AGE_DAYS)
+        .fetch_optional(&app.db)
+        .await?;
+
+    Ok(profile.map(|profile| MinecraftUserData {
+        minecraft_username: profile.name,
+        properties: profile.properties.0,
+    }))
+}
+
+pub async fn get_by_name(app: &State<App>, username: &str) -> Result<Option<MinecraftUsernameToUuid>, ApiError> {
+    let profile = query!("SELECT uuid, name FROM minecraft_profiles
+                          WHERE LOWER(name) = LOWER($1) AND fetched_at > NOW() - MAKE_INTERVAL(days => $2)
+                          ORDER BY fetched_at DESC
+                          LIMIT 1", username, MAX_AGE_DAYS)
+        .fetch_optional(&app.db)
+        .await?;
+
+    Ok(profile.map(|profile| MinecraftUsernameToUuid {
+        name: profile.name,
+        id: profile.uuid,
+    }))
+}
+
+pub async fn store_profile(app: &State<App>, uuid: Uuid, profile: &MinecraftUserData) -> Result<(), ApiError> {
+    query!("INSERT INTO minecraft_profiles (uuid, name, properties, fetched_at) VALUES ($1, $2, $3, NOW())
+            ON CONFLICT (uuid) DO UPDATE SET name = EXCLUDED.name, properties = EXCLUDED.properties, fetched_at = NOW()",
+        uuid, profile.minecraft_username, Json(&profile.properties) as _)
+        .execute(&app.db)
+        .await?;
+
+    Ok(())
+}
+
+/// Records the result of a username lookup. Known rows only get their name updated, their properties
+/// are left for the background refresh.
+pub async fn store_name(app: &State<App>, profile: &MinecraftUsernameToUuid) -> Result<(), ApiError> {
+    query!("INSERT INTO minecraft_profiles (uuid, name) VALUES ($1, $2)
+            ON CONFLICT (uuid) DO UPDATE SET name = EXCLUDED.name", profile.id, profile.name)
+        .execute(&app.db)
+        .await?;
+
+    Ok(())
+}
+
+/// Re-fetches the oldest stale profiles from Mojang and drops expired profiles nobody has linked.
+pub async fn refresh_stale_profiles(app: &State<App>) -> Result<u64, ApiError> {
+    query!("DELETE FROM minecraft_profiles
+            WHERE fetched_at < NOW() - MAKE_INTERVAL(days => $1)
+              AND NOT EXISTS (SELECT 1 FROM users WHERE users.minecraft_uuid = minecraft_profiles.uuid)", MAX_AGE_DAYS)
+        .execute(&app.db)
+        .await?;
+
+    let stale = query!("SELECT uuid FROM minecraft_profiles
+                        WHERE properties IS NULL OR fetched_at < NOW() - MAKE_INTERVAL(hours => $1)
+                        ORDER BY fetched_at
+                        LIMIT $2", STALE_AFTER_HOURS, MOJANG_BATCH_SIZE)
+        .fetch_all(&app.db)
+        .await?;
+
+    let mut refreshed = 0;
+
+    for row in stale {
+        match fetch_minecraft_profile(app, &row.uuid.to_string()).await {
+            Ok(profile) => {
+                store_profile(app, row.uuid, &profile).await?;
+                app.cache.id_to_username_minecraft.insert(row.uuid, Some(profile));
+                refreshed += 1;
+            },
+            Err(ApiError::NotFound) => {
+                query!("DELETE FROM minecraft_profiles WHERE uuid = $1", row.uuid)
+                    .execute(&app.db)
+                    .await?;
+            },
+            Err(err) => {
+                warn!("Failed to refresh Minecraft profile {} \n {}", row.uuid, err);
+                break;
+            },
+        }
+    }
+
+    Ok(refreshed)
+}
+
+pub async fn refresh_task(app: App) {
+    tasks::run_every(app, REFRESH_CHECK_INTERVAL, "refreshing Minecraft profiles", |app| Box::pin(refresh_stale_profiles(app)), |refreshed| {
+        if refreshed > 0 {
+            info!("Refreshed {} stale Minecraft profile(s)", refreshed);
+        }
+    }).await
+}
\ No newline at end of file
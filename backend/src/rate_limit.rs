@@ -0,0 +1,291 @@
+use rocket::fairing::AdHoc;
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use std::collections::HashMap;
+use std::env;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::app::App;
+use crate::errors::ApiError;
+
+/// Idle buckets are dropped once this many are tracked.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Allows bursts of up to `requests` requests, refilled evenly over `per_secs` seconds.
+#[derive(Clone, Copy)]
+pub struct Limit {
+    pub requests: f64,
+    pub per_secs: f64,
+}
+
+impl Limit {
+    /// Reads a limit written as `<requests>/<seconds>`, e.g. `30/60`, falling back to `default`.
+    fn from_env(name: &str, default: Limit) -> Limit {
+        let Ok(value) = env::var(name) else {
+            return default;
+        };
+
+        let parsed = value.split_once('/')
+            .and_then(|(requests, per_secs)| Some((requests.trim().parse::<f64>().ok()?, per_secs.trim().parse::<f64>().ok()?)));
+
+        match parsed {
+            Some((requests, per_secs)) if requests >= 1.0 && per_secs > 0.0 => Limit { requests, per_secs },
+            _ => {
+                warn!("Ignoring invalid rate limit {}={}, expected <requests>/<seconds>", name, value);
+                default
+            },
+        }
+    }
+
+    fn refill_rate(&self) -> f64 {
+        self.requests / self.per_secs
+    }
+}
+
+/// A group of routes sharing one set of limits.
+pub trait RateLimitClass: Send + Sync + 'static {
+    const NAME: &'static str;
+}
+
+/// Public profile lookups, which are proxied to Mojang and Discord.
+pub struct Lookup;
+
+impl RateLimitClass for Lookup {
+    const NAME: &'static str = "lookup";
+}
+
+/// Linking and changing Minecraft accounts, which issues console commands.
+pub struct WhitelistChange;
+
+impl RateLimitClass for WhitelistChange {
+    const NAME: &'static str = "whitelist";
+}
+
+#[derive(Clone, Copy)]
+struct ClassLimits {
+    session: Limit,
+    ip: Limit,
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+enum BucketKey {
+    Session(Uuid),
+    Ip(IpAddr),
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.refill_rate()).min(limit.requests);
+        self.updated_at = now;
+    }
+
+    /// Seconds until a token is available, zero if one is available now.
+    fn wait_secs(&self, limit: &Limit) -> f64 {
+        if self.tokens >= 1.0 {
+            0.0
+        } else {
+            (1.0 - self.tokens) / limit.refill_rate()
+        }
+    }
+}
+
+/// Per-session and per-IP token buckets for every rate limit class.
+pub struct RateLimiter {
+    limits: HashMap<&'static str, ClassLimits>,
+    buckets: Mutex<HashMap<(&'static str, BucketKey), TokenBucket>>,
+}
+
+impl RateLimiter {
+    /// Limits are configured through `RATE_LIMIT_<CLASS>_SESSION` and `RATE_LIMIT_<CLASS>_IP`. IP limits are
+    /// looser since players behind the same NAT share an address.
+    pub fn from_env() -> Self {
+        let mut limits = HashMap::new();
+
+        limits.insert(Lookup::NAME, ClassLimits {
+            session: Limit::from_env("RATE_LIMIT_LOOKUP_SESSION", Limit { requests: 30.0, per_secs: 60.0 }),
+            ip: Limit::from_env("RATE_LIMIT_LOOKUP_IP", Limit { requests: 120.0, per_secs: 60.0 }),
+        });
+        limits.insert(WhitelistChange::NAME, ClassLimits {
+            session: Limit::from_env("RATE_LIMIT_WHITELIST_SESSION", Limit { requests: 5.0, per_secs: 300.0 }),
+            ip: Limit::from_env("RATE_LIMIT_WHITELIST_IP", Limit { requests: 20.0, per_secs: 300.0 }),
+        });
+
+        Self { limits, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Takes a token from every bucket that applies, or returns how many seconds to wait if any of them is empty.
+    fn acquire(&self, class: &'static str, session: Option<Uuid>, ip: Option<IpAddr>) -> Result<(), u64> {
+        let Some(limits) = self.limits.get(class) else {
+            return Ok(());
+        };
+
+        let keys = [
+            session.map(|session| (BucketKey::Session(session), limits.session)),
+            ip.map(|ip| (BucketKey::Ip(ip), limits.ip)),
+        ];
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if buckets.len() >= MAX_BUCKETS {
+            let limits = &self.limits;
+            buckets.retain(|(class, _), bucket| {
+                let Some(class_limits) = limits.get(class) else {
+                    return false;
+                };
+                // Buckets that would have refilled completely behave just like new ones.
+                let max_requests = class_limits.session.requests.max(class_limits.ip.requests);
+                let min_rate = class_limits.session.refill_rate().min(class_limits.ip.refill_rate());
+                bucket.tokens + now.duration_since(bucket.updated_at).as_secs_f64() * min_rate < max_requests
+            });
+        }
+
+        let mut wait_secs: f64 = 0.0;
+
+        for (key, limit) in keys.iter().flatten() {
+            let bucket = buckets.entry((class, *key)).or_insert(TokenBucket { tokens: limit.requests, updated_at: now });
+            bucket.refill(limit, now);
+            wait_secs = wait_secs.max(bucket.wait_secs(limit));
+        }
+
+        if wait_secs > 0.0 {
+            return Err(wait_secs.ceil() as u64);
+        }
+
+        for (key, _) in keys.iter().flatten() {
+            if let Some(bucket) = buckets.get_mut(&(class, *key)) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Seconds the client has to wait, stashed by the guard for the `Retry-After` fairing.
+struct RetryAfter(Option<u64>);
+
+/// Request guard that rejects the request with 429 once the session or the client IP runs out of tokens for `C`.
+pub struct RateLimit<C: RateLimitClass>(PhantomData<C>);
+
+#[rocket::async_trait]
+impl<'r, C: RateLimitClass> FromRequest<'r> for RateLimit<C> {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(app) = request.rocket().state::<App>() else {
+            return Outcome::Error((Status::InternalServerError, ApiError::OptionError));
+        };
+
+        let session = request.cookies()
+            .get_private("session_id")
+            .and_then(|cookie| Uuid::parse_str(cookie.value()).ok());
+
+        match app.rate_limiter.acquire(C::NAME, session, request.client_ip()) {
+            Ok(()) => Outcome::Success(RateLimit(PhantomData)),
+            Err(retry_after) => {
+                request.local_cache(|| RetryAfter(Some(retry_after)));
+                Outcome::Error((Status::TooManyRequests, ApiError::RateLimited))
+            },
+        }
+    }
+}
+
+/// Adds the `Retry-After` header to responses of rate limited requests.
+pub fn retry_after_fairing() -> AdHoc {
+    AdHoc::on_response("Retry-After", |request, response| Box::pin(async move {
+        if let RetryAfter(Some(retry_after)) = request.local_cache(|| RetryAfter(None)) {
+            response.set_header(Header::new("Retry-After", retry_after.to_string()));
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::*;
+
+    const LIMIT: Limit = Limit { requests: 2.0, per_secs: 60.0 };
+
+    fn limiter(session: Limit, ip: Limit) -> RateLimiter {
+        RateLimiter {
+            limits: HashMap::from([(Lookup::NAME, ClassLimits { session, ip })]),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn bucket_refills_at_rate_up_to_capacity() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket { tokens: 0.0, updated_at: start };
+
+        // Two requests per minute refill one token every 30 seconds.
+        assert_eq!(bucket.wait_secs(&LIMIT), 30.0);
+
+        bucket.refill(&LIMIT, start + Duration::from_secs(15));
+        assert_eq!(bucket.tokens, 0.5);
+        assert_eq!(bucket.wait_secs(&LIMIT), 15.0);
+
+        bucket.refill(&LIMIT, start + Duration::from_secs(600));
+        assert_eq!(bucket.tokens, LIMIT.requests);
+        assert_eq!(bucket.wait_secs(&LIMIT), 0.0);
+    }
+
+    #[test]
+    fn rejects_with_retry_after_once_empty() {
+        let limiter = limiter(LIMIT, Limit { requests: 100.0, per_secs: 60.0 });
+        let session = Some(Uuid::new_v4());
+
+        assert_eq!(limiter.acquire(Lookup::NAME, session, None), Ok(()));
+        assert_eq!(limiter.acquire(Lookup::NAME, session, None), Ok(()));
+        assert_eq!(limiter.acquire(Lookup::NAME, session, None), Err(30));
+
+        // Other sessions have their own bucket.
+        assert_eq!(limiter.acquire(Lookup::NAME, Some(Uuid::new_v4()), None), Ok(()));
+    }
+
+    #[test]
+    fn ip_limit_applies_across_sessions() {
+        let limiter = limiter(Limit { requests: 100.0, per_secs: 60.0 }, LIMIT);
+        let ip = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        assert_eq!(limiter.acquire(Lookup::NAME, Some(Uuid::new_v4()), ip), Ok(()));
+        assert_eq!(limiter.acquire(Lookup::NAME, Some(Uuid::new_v4()), ip), Ok(()));
+        assert_eq!(limiter.acquire(Lookup::NAME, Some(Uuid::new_v4()), ip), Err(30));
+    }
+
+    #[test]
+    fn rejected_requests_do_not_take_tokens() {
+        let limiter = limiter(LIMIT, Limit { requests: 1.0, per_secs: 60.0 });
+        let session = Uuid::new_v4();
+        let ip = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        assert_eq!(limiter.acquire(Lookup::NAME, Some(session), ip), Ok(()));
+        assert_eq!(limiter.acquire(Lookup::NAME, Some(session), ip), Err(60));
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets[&(Lookup::NAME, BucketKey::Session(session))].tokens >= 1.0);
+    }
+
+    #[test]
+    fn unknown_classes_are_not_limited() {
+        let limiter = limiter(LIMIT, LIMIT);
+
+        for _ in 0..10 {
+            assert_eq!(limiter.acquire(WhitelistChange::NAME, Some(Uuid::nil()), None), Ok(()));
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,136 @@
+use rocket::State;
+use serde::Serialize;
+use sqlx::query;
+use std::collections::HashSet;
+use std::env;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::app::App;
+use crate::errors::ApiError;
+use crate::fetch_minecraft_profile;
+use crate::minecraft::{self, CommandResult, WhitelistEntry};
+use crate::servers::{self, MinecraftServer};
+use crate::tasks;
+
+const DEFAULT_RECONCILE_INTERVAL_SECS: u64 = 3600;
+
+#[derive(Serialize)]
+pub struct ReconcileReport {
+    pub server: String,
+    /// Linked, non-banned, eligible accounts that the server's whitelist policy admits but that are missing from its whitelist.
+    pub missing: Vec<Uuid>,
+    /// Whitelist entries that don't belong to any account the server's whitelist policy admits.
+    pub extra: Vec<WhitelistEntry>,
+    /// Whitelisted names that couldn't be resolved to a Minecraft account. They can't be diffed, so they're left alone.
+    pub unresolved: Vec<String>,
+    /// Commands issued to fix the drift, empty unless fixing was requested.
+    pub commands: Vec<CommandResult>,
+    /// Why the server couldn't be reconciled, the other fields are empty then.
+    pub error: Option<String>,
+}
+
+/// Diffs the whitelist of every enabled, policy-managed server against the linked, non-banned, eligible users,
+/// optionally issuing the whitelist commands needed to bring the servers back in line with the database.
+pub async fn reconcile(app: &State<App>, fix: bool) -> Result<Vec<ReconcileReport>, ApiError> {
+    let users = query!(r#"SELECT minecraft_uuid AS "minecraft_uuid!", is_admin FROM users WHERE minecraft_uuid IS NOT NULL AND banned = false AND eligible = true"#)
+        .fetch_all(&app.db)
+        .await?;
+
+    let mut reports = Vec::new();
+
+    for server in servers::get_enabled_servers(app).await? {
+        if server.whitelist_policy == servers::POLICY_NONE {
+            continue;
+        }
+
+        let expected = users.iter()
+            .filter(|user| server.allows(user.is_admin))
+            .map(|user| user.minecraft_uuid)
+            .collect::<HashSet<Uuid>>();
+
+        // One unreachable server shouldn't keep the others from being checked.
+        match reconcile_server(app, &server, &expected, fix).await {
+            Ok(report) => reports.push(report),
+            Err(err) => {
+                error!("A unknown error occurred while reconciling the whitelist of {} \n {}", server.name, err);
+                reports.push(ReconcileReport {
+                    server: server.name.clone(),
+                    missing: Vec::new(),
+                    extra: Vec::new(),
+                    unresolved: Vec::new(),
+                    commands: Vec::new(),
+                    error: Some(err.to_string()),
+                });
+            },
+        }
+    }
+
+    Ok(reports)
+}
+
+async fn reconcile_server(app: &State<App>, server: &MinecraftServer, expected: &HashSet<Uuid>, fix: bool) -> Result<ReconcileReport, ApiError> {
+    let whitelist = minecraft::read_whitelist(app, server).await?;
+    let whitelisted = whitelist.entries.iter().map(|entry| entry.uuid).collect::<HashSet<Uuid>>();
+
+    let mut missing = expected.difference(&whitelisted).copied().collect::<Vec<Uuid>>();
+    missing.sort();
+
+    let extra = whitelist.entries.into_iter()
+        .filter(|entry| !expected.contains(&entry.uuid))
+        .collect::<Vec<WhitelistEntry>>();
+
+    let mut commands = Vec::new();
+
+    if fix {
+        for uuid in &missing {
+            match fetch_minecraft_profile(app, &uuid.to_string()).await {
+                Ok(profile) => commands.push(minecraft::minecraft_whitelist_on(app, server, &profile.minecraft_username).await),
+                Err(err) => commands.push(CommandResult::failed(format!("whitelist add {}", uuid), err.to_string())),
+            }
+        }
+
+        for entry in &extra {
+            commands.push(minecraft::minecraft_whitelist_remove_on(app, server, &entry.name).await);
+        }
+    }
+
+    Ok(ReconcileReport { server: server.name.clone(), missing, extra, unresolved: whitelist.unresolved, commands, error: None })
+}
+
+/// Reads `WHITELIST_RECONCILE_INTERVAL` in seconds, falling back to the default.
+fn interval_from_env() -> u64 {
+    let Ok(value) = env::var("WHITELIST_RECONCILE_INTERVAL") else {
+        return DEFAULT_RECONCILE_INTERVAL_SECS;
+    };
+
+    // A zero period would make the interval panic.
+    match value.trim().parse::<u64>() {
+        Ok(secs) if secs > 0 => secs,
+        _ => {
+            warn!("Ignoring invalid WHITELIST_RECONCILE_INTERVAL={}, expected a positive number of seconds", value);
+            DEFAULT_RECONCILE_INTERVAL_SECS
+        },
+    }
+}
+
+/// Periodically checks for whitelist drift. Drift is only logged unless `WHITELIST_RECONCILE_FIX` is enabled.
+pub async fn reconcile_task(app: App) {
+    let interval = Duration::from_secs(interval_from_env());
+    let fix = env::var("WHITELIST_RECONCILE_FIX")
+        .map(|value| value == "true")
+        .unwrap_or(false);
+
+    tasks::run_every(app, interval, "reconciling the whitelist", move |app| Box::pin(reconcile(app, fix)), move |reports| {
+        for report in reports.iter().filter(|report| !report.missing.is_empty() || !report.extra.is_empty() || !report.unresolved.is_empty()) {
+            warn!(
+                "Whitelist drift detected on {}: {} missing, {} extra, {} unresolved{}",
+                report.server,
+                report.missing.len(),
+                report.extra.len(),
+                report.unresolved.len(),
+                if fix { " (fixed)" } else { "" },
+            );
+        }
+    }).await
+}
\ No newline at end of file
@@ -0,0 +1,166 @@
+use reqwest::StatusCode;
+use rocket::State;
+use serde::Deserialize;
+use sqlx::query;
+use std::env;
+use std::time::Duration;
+
+use crate::app::App;
+use crate::command_queue::{backoff_secs, MAX_ATTEMPTS};
+use crate::errors::ApiError;
+use crate::tasks;
+
+const RETRY_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// JSON error codes Discord sends along with a 404 from the member role endpoints.
+const UNKNOWN_MEMBER: u32 = 10007;
+const UNKNOWN_ROLE: u32 = 10011;
+
+#[derive(Deserialize)]
+struct DiscordError {
+    code: u32,
+}
+
+/// The Discord roles held by every linked, non-banned, eligible user.
+#[derive(Clone)]
+pub struct RoleSync {
+    pub guild_id: String,
+    pub role_ids: Vec<String>,
+}
+
+impl RoleSync {
+    /// Reads `DISCORD_GUILD_ID` and the comma separated `DISCORD_LINKED_ROLE_IDS`, role sync is off unless both are set.
+    pub fn from_env() -> Option<Self> {
+        let role_ids = env::var("DISCORD_LINKED_ROLE_IDS").ok()?
+            .split(',')
+            .map(|role| role.trim().to_string())
+            .filter(|role| !role.is_empty())
+            .collect::<Vec<String>>();
+
+        if role_ids.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            guild_id: env::var("DISCORD_GUILD_ID").ok()?,
+            role_ids,
+        })
+    }
+}
+
+/// Grants or removes the linked roles so they match the user's current state in the database.
+async fn try_sync(app: &State<App>, discord_id: i64) -> Result<(), ApiError> {
+    let (Some(sync), Some(bot)) = (&app.role_sync, &app.discord_bot) else {
+        return Ok(());
+    };
+
+    let user = query!(r#"SELECT (minecraft_uuid IS NOT NULL AND banned = false AND eligible = true) AS "linked!"
+                         FROM users WHERE discord_id = $1"#, discord_id)
+        .fetch_optional(&app.db)
+        .await?;
+
+    let Some(user) = user else {
+        return Ok(());
+    };
+
+    let mut unknown_roles = Vec::new();
+
+    for role_id in &sync.role_ids {
+        let url = format!("https://discord.com/api/guilds/{}/members/{}/roles/{}", sync.guild_id, discord_id, role_id);
+        let request = if user.linked { bot.put(url) } else { bot.delete(url) };
+
+        let response = request.send().await?;
+
+        let Err(err) = response.error_for_status_ref() else {
+            continue;
+        };
+
+        if response.status() != StatusCode::NOT_FOUND {
+            return Err(err.into());
+        }
+
+        match response.json::<DiscordError>().await.map(|error| error.code) {
+            // The user isn't in the guild, there's nobody to give any of the roles to.
+            Ok(UNKNOWN_MEMBER) => break,
+            // A misconfigured role shouldn't keep the other roles from being synced.
+            Ok(UNKNOWN_ROLE) => unknown_roles.push(role_id.as_str()),
+            _ => return Err(err.into()),
+        }
+    }
+
+    if !unknown_roles.is_empty() {
+        return Err(ApiError::Discord(format!(
+            "Role(s) {} from DISCORD_LINKED_ROLE_IDS don't exist in guild {}", unknown_roles.join(", "), sync.guild_id
+        )));
+    }
+
+    Ok(())
+}
+
+/// Syncs the user's linked roles, queueing a retry when Discord can't be reached.
+pub async fn sync_user(app: &State<App>, discord_id: i64) {
+    let result = match try_sync(app, discord_id).await {
+        Ok(()) => query!("DELETE FROM pending_role_syncs WHERE discord_id = $1", discord_id)
+            .execute(&app.db)
+            .await
+            .map(|_| ()),
+        Err(err) => {
+            warn!("Failed to sync the Discord roles of {}, queueing a retry \n {}", discord_id, err);
+
+            query!("INSERT INTO pending_role_syncs (discord_id, last_error, next_attempt_at)
+                    VALUES ($1, $2, NOW() + make_interval(secs => $3))
+                    ON CONFLICT (discord_id) DO UPDATE
+                    SET attempts = 1, last_error = EXCLUDED.last_error, next_attempt_at = EXCLUDED.next_attempt_at",
+                discord_id, err.to_string(), backoff_secs(1) as f64)
+                .execute(&app.db)
+                .await
+                .map(|_| ())
+        },
+    };
+
+    if let Err(err) = result {
+        error!("A unknown error occurred while queueing a Discord role sync \n {}", err);
+    }
+}
+
+async fn retry_due_syncs(app: &State<App>) -> Result<usize, ApiError> {
+    let due = query!("SELECT discord_id, attempts FROM pending_role_syncs
+                      WHERE next_attempt_at <= NOW() AND attempts < $1
+                      ORDER BY next_attempt_at", MAX_ATTEMPTS)
+        .fetch_all(&app.db)
+        .await?;
+
+    let mut completed = 0;
+
+    for sync in due {
+        match try_sync(app, sync.discord_id).await {
+            Ok(()) => {
+                query!("DELETE FROM pending_role_syncs WHERE discord_id = $1", sync.discord_id)
+                    .execute(&app.db)
+                    .await?;
+
+                completed += 1;
+            },
+            Err(err) => {
+                let attempts = sync.attempts + 1;
+
+                query!("UPDATE pending_role_syncs
+                        SET attempts = $2, last_error = $3, next_attempt_at = NOW() + make_interval(secs => $4)
+                        WHERE discord_id = $1",
+                    sync.discord_id, attempts, err.to_string(), backoff_secs(attempts) as f64)
+                    .execute(&app.db)
+                    .await?;
+            },
+        }
+    }
+
+    Ok(completed)
+}
+
+pub async fn retry_task(app: App) {
+    tasks::run_every(app, RETRY_CHECK_INTERVAL, "retrying Discord role syncs", |app| Box::pin(retry_due_syncs(app)), |completed| {
+        if completed > 0 {
+            info!("Retried {} queued Discord role sync(s)", completed);
+        }
+    }).await
+}
\ No newline at end of file
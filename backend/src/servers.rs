@@ -0,0 +1,199 @@
+use chrono::serde::ts_seconds;
+use chrono::{DateTime, Utc};
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, PgConnection, Pool, Postgres};
+use std::env;
+
+use crate::app::App;
+use crate::errors::ApiError;
+
+/// Every linked, non-banned user gets whitelisted.
+pub const POLICY_ALL: &str = "all";
+/// Only linked, non-banned admins get whitelisted.
+pub const POLICY_ADMINS: &str = "admins";
+/// The whitelist is managed by hand; nobody gets whitelisted automatically.
+pub const POLICY_NONE: &str = "none";
+
+/// Commands go through the Pterodactyl panel, needs `pterodactyl_id`.
+pub const BACKEND_PTERODACTYL: &str = "pterodactyl";
+/// Commands go straight to the server over RCON, needs `rcon_address` and `rcon_password`.
+pub const BACKEND_RCON: &str = "rcon";
+
+#[derive(Serialize, Clone)]
+pub struct MinecraftServer {
+    pub id: i32,
+    pub name: String,
+    pub pterodactyl_id: Option<String>,
+    pub whitelist_policy: String,
+    pub enabled: bool,
+    #[serde(with = "ts_seconds")]
+    pub created_at: DateTime<Utc>,
+    pub backend: String,
+    pub rcon_address: Option<String>,
+    #[serde(skip_serializing)]
+    pub rcon_password: Option<String>,
+    /// Address players connect to, used for status pings. `host` or `host:port`, SRV records are not followed.
+    pub address: Option<String>,
+}
+
+impl MinecraftServer {
+    /// Whether a linked, non-banned user should be on this server's whitelist.
+    pub fn allows(&self, is_admin: bool) -> bool {
+        match self.whitelist_policy.as_str() {
+            POLICY_ALL => true,
+            POLICY_ADMINS => is_admin,
+            _ => false,
+        }
+    }
+
+    /// One-line summary for the audit log.
+    pub fn describe(&self) -> String {
+        format!("{} ({}, policy {}, {})", self.name, self.backend, self.whitelist_policy, if self.enabled { "enabled" } else { "disabled" })
+    }
+}
+
+/// A server to create, or the fields to change on update. Fields left out on update keep their current value.
+#[derive(Deserialize)]
+pub struct ServerData {
+    /// Required on create.
+    pub name: Option<String>,
+    pub whitelist_policy: Option<String>,
+    pub enabled: Option<bool>,
+    pub backend: Option<String>,
+    pub pterodactyl_id: Option<String>,
+    pub rcon_address: Option<String>,
+    /// Never sent back to the client.
+    pub rcon_password: Option<String>,
+    pub address: Option<String>,
+}
+
+impl ServerData {
+    fn validate(&self) -> Result<(), ApiError> {
+        match self.whitelist_policy.as_deref() {
+            None | Some(POLICY_ALL) | Some(POLICY_ADMINS) | Some(POLICY_NONE) => (),
+            Some(_) => return Err(ApiError::BadRequest),
+        }
+
+        match self.backend.as_deref() {
+            None | Some(BACKEND_PTERODACTYL) | Some(BACKEND_RCON) => Ok(()),
+            Some(_) => Err(ApiError::BadRequest),
+        }
+    }
+}
+
+fn map_server_error(err: sqlx::Error) -> ApiError {
+    match err {
+        sqlx::Error::Database(err) if err.constraint() == Some("servers_name_key") => ApiError::CollisionError,
+        sqlx::Error::Database(err) if err.constraint() == Some("server_backend_config") => ApiError::BadRequest,
+        err => err.into(),
+    }
+}
+
+/// Registers a "main" server from `PTERODACTYL_SERVER_ID` (or `RCON_ADDRESS` and `RCON_PASSWORD`) and
+/// `MINECRAFT_SERVER_ADDRESS` when no servers are configured yet, so single-server deployments keep
+/// working without touching the database.
+pub async fn seed_default_server(db: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    let (backend, pterodactyl_id, rcon_address, rcon_password) = match (
+        env::var("PTERODACTYL_SERVER_ID"),
+        env::var("RCON_ADDRESS"),
+        env::var("RCON_PASSWORD"),
+    ) {
+        (Ok(pterodactyl_id), _, _) => (BACKEND_PTERODACTYL, Some(pterodactyl_id), None, None),
+        (_, Ok(address), Ok(password)) => (BACKEND_RCON, None, Some(address), Some(password)),
+        _ => return Ok(()),
+    };
+    let address = env::var("MINECRAFT_SERVER_ADDRESS").ok();
+
+    // Servers registered before status pings existed don't have an address yet.
+    query!("UPDATE servers SET address = $1 WHERE name = 'main' AND address IS NULL", address)
+        .execute(db)
+        .await?;
+
+    let server = query!("INSERT INTO servers (name, backend, pterodactyl_id, rcon_address, rcon_password, address)
+                         SELECT 'main', $1, $2, $3, $4, $5
+                         WHERE NOT EXISTS (SELECT 1 FROM servers)
+                         RETURNING id", backend, pterodactyl_id, rcon_address, rcon_password, address)
+        .fetch_optional(db)
+        .await?;
+
+    if let Some(server) = server {
+        query!("UPDATE pending_commands SET server_id = $1 WHERE server_id IS NULL", server.id)
+            .execute(db)
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn get_servers(app: &State<App>) -> Result<Vec<MinecraftServer>, ApiError> {
+    Ok(query_as!(MinecraftServer, "SELECT * FROM servers ORDER BY id")
+        .fetch_all(&app.db)
+        .await?)
+}
+
+pub async fn get_enabled_servers(app: &State<App>) -> Result<Vec<MinecraftServer>, ApiError> {
+    Ok(query_as!(MinecraftServer, "SELECT * FROM servers WHERE enabled = true ORDER BY id")
+        .fetch_all(&app.db)
+        .await?)
+}
+
+pub async fn get_server(app: &State<App>, id: i32) -> Result<MinecraftServer, ApiError> {
+    query_as!(MinecraftServer, "SELECT * FROM servers WHERE id = $1", id)
+        .fetch_optional(&app.db)
+        .await?
+        .ok_or(ApiError::NotFound)
+}
+
+/// Finds the server to report the status of: the one called `name`, or else the first enabled server with an address.
+pub async fn get_status_server(app: &State<App>, name: Option<&str>) -> Result<MinecraftServer, ApiError> {
+    query_as!(MinecraftServer, "SELECT * FROM servers
+                                WHERE enabled = true AND address IS NOT NULL AND ($1::TEXT IS NULL OR name = $1)
+                                ORDER BY id
+                                LIMIT 1", name)
+        .fetch_optional(&app.db)
+        .await?
+        .ok_or(ApiError::NotFound)
+}
+
+pub async fn create_server(conn: &mut PgConnection, data: ServerData) -> Result<MinecraftServer, ApiError> {
+    data.validate()?;
+    let name = data.name.ok_or(ApiError::BadRequest)?;
+
+    query_as!(MinecraftServer, "INSERT INTO servers (name, whitelist_policy, enabled, backend, pterodactyl_id, rcon_address, rcon_password, address)
+                                VALUES ($1, COALESCE($2, 'all'), COALESCE($3, true), COALESCE($4, 'pterodactyl'), $5, $6, $7, $8)
+                                RETURNING *",
+        name, data.whitelist_policy, data.enabled, data.backend, data.pterodactyl_id, data.rcon_address, data.rcon_password, data.address)
+        .fetch_one(conn)
+        .await
+        .map_err(map_server_error)
+}
+
+/// Changes the fields present in `data`, so toggling a single field doesn't need the whole server.
+pub async fn update_server(conn: &mut PgConnection, id: i32, data: ServerData) -> Result<MinecraftServer, ApiError> {
+    data.validate()?;
+
+    query_as!(MinecraftServer, "UPDATE servers
+                                SET name = COALESCE($2, name),
+                                    whitelist_policy = COALESCE($3, whitelist_policy),
+                                    enabled = COALESCE($4, enabled),
+                                    backend = COALESCE($5, backend),
+                                    pterodactyl_id = COALESCE($6, pterodactyl_id),
+                                    rcon_address = COALESCE($7, rcon_address),
+                                    rcon_password = COALESCE($8, rcon_password),
+                                    address = COALESCE($9, address)
+                                WHERE id = $1
+                                RETURNING *",
+        id, data.name, data.whitelist_policy, data.enabled, data.backend, data.pterodactyl_id, data.rcon_address, data.rcon_password, data.address)
+        .fetch_optional(conn)
+        .await
+        .map_err(map_server_error)?
+        .ok_or(ApiError::NotFound)
+}
+
+pub async fn delete_server(conn: &mut PgConnection, id: i32) -> Result<MinecraftServer, ApiError> {
+    query_as!(MinecraftServer, "DELETE FROM servers WHERE id = $1 RETURNING *", id)
+        .fetch_optional(conn)
+        .await?
+        .ok_or(ApiError::NotFound)
+}
\ No newline at end of file
@@ -1,57 +1,455 @@
-use chrono::{Duration, Local};
+use chrono::serde::{ts_seconds, ts_seconds_option};
+use chrono::{DateTime, Duration, Utc};
 use rocket::http::{Cookie, SameSite};
 use rocket::{time, State};
-use sqlx::query;
-use std::env;
+use serde::Serialize;
+use sqlx::{query, query_as};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
 use uuid::Uuid;
 
 use crate::app::App;
-use crate::DiscordCallback;
+use crate::errors::ApiError;
+use crate::tasks;
+use crate::{DiscordAccessTokenResponse, DiscordCallback, DiscordUserData};
 
-pub async fn generate_session<'a>(app: &State<App>, access_token: &str, refresh_token: &str, token_expiry: i64) -> Cookie<'a> {
+/// Sessions get a fresh Discord token once they are this close to expiring.
+const REFRESH_BEFORE_EXPIRY: Duration = Duration::days(1);
+/// How long the cookie of a rotated session keeps working, for requests that were already in flight.
+const ROTATION_GRACE: Duration = Duration::seconds(60);
+/// `last_seen_at` is only written once it is older than this, so not every request costs a write.
+const LAST_SEEN_PRECISION_SECS: f64 = 300.0;
+const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
+const CLEANUP_BATCH_SIZE: i64 = 100;
+/// Dead sessions are deleted after this long even if their tokens couldn't be revoked.
+const ABANDON_AFTER_DAYS: i32 = 30;
+
+pub struct StoredSession {
+    pub session_id: Uuid,
+    pub user_id: i64,
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_at: DateTime<Utc>,
+    pub expired: bool,
+    pub replaced_by: Option<Uuid>,
+    pub rotated_at: Option<DateTime<Utc>>,
+    pub scope: Option<String>,
+}
+
+impl StoredSession {
+    /// Whether the session's token was granted every scope in `scopes`. Unknown for old sessions, so they don't.
+    pub fn has_scopes(&self, scopes: &[&str]) -> bool {
+        let Some(scope) = &self.scope else {
+            return false;
+        };
+
+        scopes.iter().all(|required| scope.split(' ').any(|granted| granted == *required))
+    }
+}
+
+/// A session as shown to its owner. `id` identifies it without giving away the `session_id` from the cookie.
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub id: i32,
+    #[serde(with = "ts_seconds")]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "ts_seconds_option")]
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    #[serde(with = "ts_seconds")]
+    pub expires_at: DateTime<Utc>,
+    /// Whether this is the session making the request.
+    pub current: bool,
+}
+
+/// Running totals of the session cleanup task, shared by every clone of `App`.
+#[derive(Default)]
+pub struct CleanupStats {
+    runs: AtomicU64,
+    last_run_at: AtomicI64,
+    sessions_purged: AtomicU64,
+    tokens_revoked: AtomicU64,
+    revoke_failures: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct CleanupReport {
+    pub runs: u64,
+    /// Unix timestamp of the last finished run, `None` until the task has run once.
+    pub last_run_at: Option<i64>,
+    pub sessions_purged: u64,
+    pub tokens_revoked: u64,
+    pub revoke_failures: u64,
+}
+
+impl CleanupStats {
+    pub fn report(&self) -> CleanupReport {
+        let last_run_at = self.last_run_at.load(Ordering::Relaxed);
+
+        CleanupReport {
+            runs: self.runs.load(Ordering::Relaxed),
+            last_run_at: (last_run_at > 0).then_some(last_run_at),
+            sessions_purged: self.sessions_purged.load(Ordering::Relaxed),
+            tokens_revoked: self.tokens_revoked.load(Ordering::Relaxed),
+            revoke_failures: self.revoke_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SessionCounts {
+    pub active: i64,
+    /// Expired, logged out or rotated sessions the cleanup task hasn't deleted yet.
+    pub pending_cleanup: i64,
+}
+
+fn session_cookie<'a>(session_id: Uuid, max_age_secs: i64) -> Cookie<'a> {
+    Cookie::build(("session_id", session_id.to_string()))
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(max_age_secs))
+        .build()
+}
+
+/// Stores a new session for the Discord user behind `access_token`, refreshing their profile on the way.
+async fn create_session(app: &State<App>, access_token: &str, refresh_token: &str, token_expiry: i64, scope: Option<&str>) -> Result<Uuid, ApiError> {
     let callback = app.https.get("https://discord.com/api/users/@me")
         .header("Authorization", format!("Bearer {}", access_token))
         .send()
-        .await
-        .unwrap()
+        .await?
+        .error_for_status()?
         .json::<DiscordCallback>()
-        .await
-        .unwrap();
+        .await?;
+
+    // The session is still worth handing out if only the profile refresh failed.
+    if let Err(err) = upsert_user(app, &callback).await {
+        error!("A unknown error occurred while updating a Discord profile \n {}", err);
+    }
 
-    generate_session_with_callback(app, callback, access_token, refresh_token, token_expiry).await
+    insert_session(app, callback.id.parse::<i64>()?, access_token, refresh_token, token_expiry, scope).await
 }
 
-pub async fn generate_session_with_callback<'a>(app: &State<App>, discord_callback: DiscordCallback, access_token: &str, refresh_token: &str, token_expiry: i64) -> Cookie<'a> {
-    let secs: i64 = token_expiry.try_into().unwrap();
+pub async fn generate_session_with_callback<'a>(app: &State<App>, discord_callback: DiscordCallback, access_token: &str, refresh_token: &str, token_expiry: i64, scope: Option<&str>) -> Result<Cookie<'a>, ApiError> {
+    let session_id = insert_session(app, discord_callback.id.parse::<i64>()?, access_token, refresh_token, token_expiry, scope).await?;
 
-    let max_age = Local::now().naive_local() + Duration::seconds(secs);
+    Ok(session_cookie(session_id, token_expiry))
+}
 
+async fn insert_session(app: &State<App>, user_id: i64, access_token: &str, refresh_token: &str, token_expiry: i64, scope: Option<&str>) -> Result<Uuid, ApiError> {
     let session_id = Uuid::new_v4();
 
-    let user_id = discord_callback.id.parse::<i64>().expect("Failed to read user.id as a i64");
+    // Same clock as `rotate_session` and the expiry checks, independent of the host's time zone.
+    query!("INSERT INTO sessions (user_id, session_id, expires_at, access_token, refresh_token, scope)
+            VALUES ($1, $2, NOW() + make_interval(secs => $3), $4, $5, $6)",
+        user_id, session_id, token_expiry as f64, access_token, refresh_token, scope)
+        .execute(&app.db)
+        .await?;
+
+    Ok(session_id)
+}
 
-    query!("INSERT INTO sessions (user_id, session_id, expires_at, access_token, refresh_token)
-            VALUES ($1, $2, $3, $4, $5)",
-        user_id, session_id, max_age.and_utc(), access_token, refresh_token)
+/// Trades the session's refresh token for a new access token and hands out a new session for it. The old
+/// session is only expired once the new one exists, so a failed refresh leaves the user logged in.
+pub async fn refresh_session<'a>(app: &State<App>, session_id: Uuid, refresh_token: &str) -> Result<Cookie<'a>, ApiError> {
+    let token = exchange_refresh_token(app, refresh_token).await?;
+
+    let new_session_id = create_session(app, &token.access_token, &token.refresh_token, token.expires_in, token.scope.as_deref()).await?;
+
+    // Marked as replaced so the cleanup task doesn't revoke its tokens, which would take the new session down too.
+    query!("UPDATE sessions SET expired = true, replaced_by = $2, rotated_at = NOW() WHERE session_id = $1", session_id, new_session_id)
         .execute(&app.db)
-        .await
-        .unwrap();
+        .await?;
 
-    Cookie::build(("session_id", session_id.to_string()))
-        .same_site(SameSite::Lax)
-        .max_age(time::Duration::seconds(secs))
-        .build()
+    Ok(session_cookie(new_session_id, token.expires_in))
+}
+
+async fn exchange_refresh_token(app: &State<App>, refresh_token: &str) -> Result<DiscordAccessTokenResponse, ApiError> {
+    Ok(app.https.post("https://discord.com/api/oauth2/token")
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .basic_auth(&app.discord_client_id, Some(&app.discord_client_secret))
+        .body(format!("grant_type=refresh_token&refresh_token={}", refresh_token))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<DiscordAccessTokenResponse>()
+        .await?)
 }
 
-pub async fn revoke_discord_token(app: &State<App>, token: String) {
+async fn get_session(app: &State<App>, session_id: Uuid) -> Result<Option<StoredSession>, ApiError> {
+    Ok(query_as!(StoredSession, "SELECT session_id, user_id, access_token, refresh_token, expires_at, expired, replaced_by, rotated_at, scope
+                                 FROM sessions WHERE session_id = $1", session_id)
+        .fetch_optional(&app.db)
+        .await?)
+}
+
+/// The session behind `session_id` if it can still be used, rotating it first when its Discord token is about to
+/// expire. Comes with the cookie to hand out if the client should switch to a different session.
+pub async fn resolve_session<'a>(app: &State<App>, session_id: Uuid) -> Result<Option<(StoredSession, Option<Cookie<'a>>)>, ApiError> {
+    let Some(session) = get_session(app, session_id).await? else {
+        return Ok(None);
+    };
+
+    let now = Utc::now();
+
+    if session.expired {
+        // A request sent just before the rotation still carries the old cookie.
+        let (Some(replaced_by), Some(rotated_at)) = (session.replaced_by, session.rotated_at) else {
+            return Ok(None);
+        };
+        if rotated_at + ROTATION_GRACE < now {
+            return Ok(None);
+        }
+
+        return Ok(get_session(app, replaced_by).await?
+            .filter(|replacement| !replacement.expired && replacement.expires_at > now)
+            .map(|replacement| {
+                let cookie = session_cookie(replacement.session_id, (replacement.expires_at - now).num_seconds());
+                (replacement, Some(cookie))
+            }));
+    }
+
+    if session.expires_at <= now {
+        return Ok(None);
+    }
+
+    if session.expires_at - now > REFRESH_BEFORE_EXPIRY {
+        return Ok(Some((session, None)));
+    }
+
+    match rotate_session(app, session_id).await {
+        Ok(Some(rotated)) => {
+            let cookie = session_cookie(rotated.session_id, (rotated.expires_at - now).num_seconds());
+            Ok(Some((rotated, Some(cookie))))
+        },
+        Ok(None) => Ok(None),
+        Err(err) => {
+            // The current token still works for a while, so the refresh is simply tried again on the next request.
+            warn!("Failed to refresh the Discord token of a session \n {}", err);
+            Ok(Some((session, None)))
+        },
+    }
+}
+
+/// Swaps the session for a new one with a refreshed Discord token. The row stays locked while Discord is asked,
+/// so concurrent requests don't spend the same refresh token twice; they get the new session instead.
+async fn rotate_session(app: &State<App>, session_id: Uuid) -> Result<Option<StoredSession>, ApiError> {
+    let mut tx = app.db.begin().await?;
+
+    let Some(session) = query_as!(StoredSession, "SELECT session_id, user_id, access_token, refresh_token, expires_at, expired, replaced_by, rotated_at, scope
+                                                  FROM sessions WHERE session_id = $1
+                                                  FOR UPDATE", session_id)
+        .fetch_optional(&mut *tx)
+        .await? else {
+        return Ok(None);
+    };
+
+    if session.expired {
+        tx.commit().await?;
+
+        return match session.replaced_by {
+            Some(replaced_by) => Ok(get_session(app, replaced_by).await?.filter(|replacement| !replacement.expired)),
+            None => Ok(None),
+        };
+    }
+
+    let token = exchange_refresh_token(app, &session.refresh_token).await?;
+
+    // The new row carries over where and when the user logged in, it's still the same session to them.
+    let rotated = query_as!(StoredSession, "INSERT INTO sessions (user_id, session_id, expires_at, access_token, refresh_token, created_at, last_seen_at, user_agent, ip, scope)
+                                            SELECT user_id, $2, NOW() + make_interval(secs => $3), $4, $5, created_at, last_seen_at, user_agent, ip, COALESCE($6, scope)
+                                            FROM sessions WHERE session_id = $1
+                                            RETURNING session_id, user_id, access_token, refresh_token, expires_at, expired, replaced_by, rotated_at, scope",
+        session_id, Uuid::new_v4(), token.expires_in as f64, token.access_token, token.refresh_token, token.scope)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    query!("UPDATE sessions SET expired = true, replaced_by = $2, rotated_at = NOW() WHERE session_id = $1", session_id, rotated.session_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(Some(rotated))
+}
+
+/// Keeps the session's last seen time, user agent and approximate IP up to date.
+pub async fn touch_session(app: &State<App>, session_id: Uuid, user_agent: Option<&str>, ip: Option<IpAddr>) -> Result<(), ApiError> {
+    query!("UPDATE sessions
+            SET last_seen_at = NOW(), user_agent = COALESCE($2, user_agent), ip = COALESCE($3, ip)
+            WHERE session_id = $1 AND (last_seen_at IS NULL OR last_seen_at < NOW() - make_interval(secs => $4))",
+        session_id, user_agent, ip.map(approximate_ip), LAST_SEEN_PRECISION_SECS)
+        .execute(&app.db)
+        .await?;
+
+    Ok(())
+}
+
+/// Drops the host part of the address, a /24 for IPv4 and a /48 for IPv6, which is enough to recognise a network.
+pub fn approximate_ip(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        },
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => approximate_ip(IpAddr::V4(ip)),
+            None => {
+                let [a, b, c, ..] = ip.segments();
+                format!("{:x}:{:x}:{:x}::/48", a, b, c)
+            },
+        },
+    }
+}
+
+/// The user's sessions that can still be used, most recently used first.
+pub async fn list_sessions(app: &State<App>, user_id: i64, current_session_id: Uuid) -> Result<Vec<SessionInfo>, ApiError> {
+    Ok(query_as!(SessionInfo, r#"SELECT id, created_at, last_seen_at, user_agent, ip, expires_at, session_id = $2 AS "current!"
+                                 FROM sessions
+                                 WHERE user_id = $1 AND expired = false AND expires_at > NOW()
+                                 ORDER BY last_seen_at DESC NULLS LAST, created_at DESC"#, user_id, current_session_id)
+        .fetch_all(&app.db)
+        .await?)
+}
+
+/// The user's active session with the given `id`, see `SessionInfo`.
+pub async fn get_user_session(app: &State<App>, user_id: i64, id: i32) -> Result<Option<StoredSession>, ApiError> {
+    Ok(query_as!(StoredSession, "SELECT session_id, user_id, access_token, refresh_token, expires_at, expired, replaced_by, rotated_at, scope
+                                 FROM sessions
+                                 WHERE id = $1 AND user_id = $2 AND expired = false AND expires_at > NOW()", id, user_id)
+        .fetch_optional(&app.db)
+        .await?)
+}
+
+/// The user's active sessions other than `current_session_id`.
+pub async fn get_other_sessions(app: &State<App>, user_id: i64, current_session_id: Uuid) -> Result<Vec<StoredSession>, ApiError> {
+    Ok(query_as!(StoredSession, "SELECT session_id, user_id, access_token, refresh_token, expires_at, expired, replaced_by, rotated_at, scope
+                                 FROM sessions
+                                 WHERE user_id = $1 AND session_id <> $2 AND expired = false AND expires_at > NOW()", user_id, current_session_id)
+        .fetch_all(&app.db)
+        .await?)
+}
+
+pub async fn count_sessions(app: &State<App>) -> Result<SessionCounts, ApiError> {
+    let counts = query!(r#"SELECT COUNT(*) FILTER (WHERE expired = false AND expires_at > NOW()) AS "active!",
+                                  COUNT(*) FILTER (WHERE expired = true OR expires_at <= NOW()) AS "pending_cleanup!"
+                           FROM sessions"#)
+        .fetch_one(&app.db)
+        .await?;
+
+    Ok(SessionCounts { active: counts.active, pending_cleanup: counts.pending_cleanup })
+}
+
+/// Revokes the Discord tokens that dead sessions still hold and deletes them, returning how many were deleted.
+/// Sessions whose tokens couldn't be revoked are kept for the next run, unless they have been dead for a long time.
+///
+/// Replaced sessions are deleted without revoking anything: a refreshed token pair belongs to the same Discord
+/// authorization, and revoking the old tokens revokes the ones of the session that replaced it as well.
+async fn purge_dead_sessions(app: &State<App>) -> Result<usize, ApiError> {
+    // Rotated sessions are kept around for the grace period, requests in flight may still use them.
+    let sessions = query!(r#"SELECT id, access_token, refresh_token,
+                                    expires_at > NOW() AS "access_token_valid!",
+                                    replaced_by IS NOT NULL AS "replaced!",
+                                    expires_at < NOW() - make_interval(days => $1) AS "abandoned!"
+                             FROM sessions
+                             WHERE expires_at <= NOW()
+                             OR (expired = true AND COALESCE(rotated_at, '-infinity') < NOW() - make_interval(secs => $2))
+                             ORDER BY id
+                             LIMIT $3"#, ABANDON_AFTER_DAYS, ROTATION_GRACE.num_seconds() as f64, CLEANUP_BATCH_SIZE)
+        .fetch_all(&app.db)
+        .await?;
+
+    let mut purged = Vec::new();
+
+    for session in sessions {
+        if session.replaced {
+            purged.push(session.id);
+            continue;
+        }
+
+        let tokens = [
+            session.access_token_valid.then_some(&session.access_token),
+            Some(&session.refresh_token),
+        ];
+
+        let mut revoked = true;
+        for token in tokens.into_iter().flatten() {
+            match revoke_discord_token(app, token).await {
+                Ok(()) => {
+                    app.session_cleanup.tokens_revoked.fetch_add(1, Ordering::Relaxed);
+                },
+                Err(err) => {
+                    warn!("Failed to revoke the Discord token of a dead session \n {}", err);
+                    app.session_cleanup.revoke_failures.fetch_add(1, Ordering::Relaxed);
+                    revoked = false;
+                },
+            }
+        }
+
+        if revoked || session.abandoned {
+            purged.push(session.id);
+        }
+    }
+
+    query!("DELETE FROM sessions WHERE id = ANY($1)", &purged)
+        .execute(&app.db)
+        .await?;
+
+    app.session_cleanup.sessions_purged.fetch_add(purged.len() as u64, Ordering::Relaxed);
+
+    Ok(purged.len())
+}
+
+/// One run of the cleanup task, counted in the cleanup stats whether it succeeds or not.
+async fn cleanup_run(app: &State<App>) -> Result<usize, ApiError> {
+    let result = purge_dead_sessions(app).await;
+
+    app.session_cleanup.runs.fetch_add(1, Ordering::Relaxed);
+    app.session_cleanup.last_run_at.store(Utc::now().timestamp(), Ordering::Relaxed);
+
+    result
+}
+
+pub async fn cleanup_task(app: App) {
+    tasks::run_every(app, CLEANUP_INTERVAL, "purging dead sessions", |app| Box::pin(cleanup_run(app)), |purged| {
+        if purged > 0 {
+            info!("Purged {} dead session(s)", purged);
+        }
+    }).await
+}
+
+/// Creates the user or refreshes their Discord username, display name and avatar, returning their Discord ID.
+pub async fn upsert_user(app: &State<App>, discord_user: &DiscordCallback) -> Result<i64, ApiError> {
+    let user_id = discord_user.id.parse::<i64>()?;
+
+    query!("INSERT INTO users (discord_id, discord_username, discord_global_name, discord_avatar)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (discord_id) DO UPDATE
+            SET discord_username = EXCLUDED.discord_username,
+                discord_global_name = EXCLUDED.discord_global_name,
+                discord_avatar = EXCLUDED.discord_avatar,
+                last_updated = NOW()",
+        user_id, discord_user.username, discord_user.global_name, discord_user.avatar)
+        .execute(&app.db)
+        .await?;
+
+    app.cache.id_to_username_discord.insert(user_id, Some(DiscordUserData {
+        discord_username: discord_user.username.clone(),
+        global_name: discord_user.global_name.clone(),
+        avatar: discord_user.avatar.clone(),
+        discriminator: discord_user.discriminator.clone(),
+    }));
+
+    Ok(user_id)
+}
+
+pub async fn revoke_discord_token(app: &State<App>, token: &str) -> Result<(), ApiError> {
     app.https.post("https://discord.com/api/oauth2/token/revoke")
         .header("Content-Type", "application/x-www-form-urlencoded")
-        .basic_auth(
-            env::var("DISCORD_CLIENT_ID").expect("Missing client id"),
-            Some(env::var("DISCORD_CLIENT_SECRET").expect("Missing client secret")),
-        )
+        .basic_auth(&app.discord_client_id, Some(&app.discord_client_secret))
         .body(format!("token={}", token))
         .send()
-        .await
-        .unwrap();
+        .await?
+        .error_for_status()?;
+
+    Ok(())
 }
\ No newline at end of file
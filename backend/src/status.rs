@@ -0,0 +1,225 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::errors::ApiError;
+
+const DEFAULT_PORT: u16 = 25565;
+/// Any protocol version works for the status state, -1 is the conventional "unknown".
+const PROTOCOL_VERSION: i32 = -1;
+const NEXT_STATE_STATUS: i32 = 1;
+const PACKET_STATUS: i32 = 0x00;
+const PACKET_PING: i32 = 0x01;
+/// Status responses carry a base64 favicon, so they can get fairly large.
+const MAX_PACKET_SIZE: i32 = 1 << 21;
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StatusPlayer {
+    pub name: String,
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StatusPlayers {
+    pub online: i64,
+    pub max: i64,
+    #[serde(default)]
+    pub sample: Vec<StatusPlayer>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ServerStatus {
+    pub server: String,
+    pub address: String,
+    pub online: bool,
+    pub players: Option<StatusPlayers>,
+    pub motd: Option<String>,
+    pub version: Option<String>,
+    pub latency_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct StatusVersion {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct StatusResponse {
+    version: Option<StatusVersion>,
+    players: Option<StatusPlayers>,
+    description: Option<Value>,
+}
+
+/// Pings `address` with the Server List Ping protocol. An unreachable server is reported as offline
+/// rather than as an error.
+pub async fn ping(server: &str, address: &str) -> ServerStatus {
+    match timeout(PING_TIMEOUT, query_status(address)).await {
+        Ok(Ok((response, latency))) => ServerStatus {
+            server: server.to_string(),
+            address: address.to_string(),
+            online: true,
+            players: response.players,
+            motd: response.description.as_ref().map(|description| strip_formatting(&flatten_text(description))),
+            version: response.version.map(|version| version.name),
+            latency_ms: Some(latency.as_millis() as u64),
+        },
+        result => {
+            if let Ok(Err(err)) = result {
+                warn!("Failed to ping Minecraft server {} \n {}", address, err);
+            }
+
+            ServerStatus {
+                server: server.to_string(),
+                address: address.to_string(),
+                online: false,
+                players: None,
+                motd: None,
+                version: None,
+                latency_ms: None,
+            }
+        },
+    }
+}
+
+/// `address` has to point at the server itself, SRV records aren't looked up.
+async fn query_status(address: &str) -> Result<(StatusResponse, Duration), ApiError> {
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().map_err(|_| ApiError::BadRequest)?),
+        None => (address, DEFAULT_PORT),
+    };
+
+    let mut stream = TcpStream::connect((host, port)).await?;
+
+    let mut handshake = Vec::new();
+    write_varint(&mut handshake, PACKET_STATUS);
+    write_varint(&mut handshake, PROTOCOL_VERSION);
+    write_string(&mut handshake, host);
+    handshake.extend_from_slice(&port.to_be_bytes());
+    write_varint(&mut handshake, NEXT_STATE_STATUS);
+    write_packet(&mut stream, &handshake).await?;
+
+    let mut request = Vec::new();
+    write_varint(&mut request, PACKET_STATUS);
+    write_packet(&mut stream, &request).await?;
+
+    let mut packet = read_packet(&mut stream).await?;
+    if read_varint_from(&mut packet)? != PACKET_STATUS {
+        return Err(ApiError::ServerControl("Unexpected status packet".to_string()));
+    }
+    let length = read_varint_from(&mut packet)? as usize;
+    let json = packet.get(..length).ok_or_else(|| ApiError::ServerControl("Truncated status packet".to_string()))?;
+    let response = serde_json::from_slice::<StatusResponse>(json)?;
+
+    let sent_at = Instant::now();
+    let mut ping = Vec::new();
+    write_varint(&mut ping, PACKET_PING);
+    ping.extend_from_slice(&0i64.to_be_bytes());
+    write_packet(&mut stream, &ping).await?;
+    read_packet(&mut stream).await?;
+
+    Ok((response, sent_at.elapsed()))
+}
+
+/// Flattens a chat component (a plain string, an object with `text`/`extra`, or a list of those) into text.
+fn flatten_text(component: &Value) -> String {
+    match component {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts.iter().map(flatten_text).collect(),
+        Value::Object(object) => {
+            let mut text = object.get("text").map(flatten_text).unwrap_or_default();
+            if let Some(extra) = object.get("extra") {
+                text.push_str(&flatten_text(extra));
+            }
+            text
+        },
+        _ => String::new(),
+    }
+}
+
+/// Removes legacy `§` formatting codes.
+fn strip_formatting(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '§' {
+            chars.next();
+        } else {
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+fn write_varint(buffer: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+
+    loop {
+        if value & !0x7F == 0 {
+            buffer.push(value as u8);
+            return;
+        }
+
+        buffer.push((value & 0x7F | 0x80) as u8);
+        value >>= 7;
+    }
+}
+
+fn write_string(buffer: &mut Vec<u8>, value: &str) {
+    write_varint(buffer, value.len() as i32);
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+async fn write_packet(stream: &mut TcpStream, packet: &[u8]) -> Result<(), ApiError> {
+    let mut buffer = Vec::with_capacity(packet.len() + 5);
+    write_varint(&mut buffer, packet.len() as i32);
+    buffer.extend_from_slice(packet);
+
+    stream.write_all(&buffer).await?;
+
+    Ok(())
+}
+
+async fn read_packet(stream: &mut TcpStream) -> Result<Vec<u8>, ApiError> {
+    let mut length: i32 = 0;
+
+    for shift in (0..35).step_by(7) {
+        let byte = stream.read_u8().await?;
+        length |= ((byte & 0x7F) as i32) << shift;
+
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+
+    if !(1..=MAX_PACKET_SIZE).contains(&length) {
+        return Err(ApiError::ServerControl(format!("Invalid status packet length {}", length)));
+    }
+
+    let mut packet = vec![0; length as usize];
+    stream.read_exact(&mut packet).await?;
+
+    Ok(packet)
+}
+
+/// Reads a VarInt off the front of `buffer`, consuming it.
+fn read_varint_from(buffer: &mut Vec<u8>) -> Result<i32, ApiError> {
+    let mut value: i32 = 0;
+
+    for (index, byte) in buffer.iter().enumerate().take(5) {
+        value |= ((byte & 0x7F) as i32) << (7 * index);
+
+        if byte & 0x80 == 0 {
+            buffer.drain(..=index);
+            return Ok(value);
+        }
+    }
+
+    Err(ApiError::ServerControl("Invalid VarInt in status packet".to_string()))
+}
\ No newline at end of file
@@ -0,0 +1,27 @@
+use rocket::tokio::time;
+use rocket::State;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use crate::app::App;
+use crate::errors::ApiError;
+
+/// One run of a background task, borrowing the app while it runs.
+pub type Run<'a, T> = Pin<Box<dyn Future<Output = Result<T, ApiError>> + Send + 'a>>;
+
+/// Runs `run` every `period` for as long as the server is up, starting right away. Successful runs are handed to
+/// `report`; failed ones are logged as an error while `activity` and the task carries on with the next tick.
+pub async fn run_every<T>(app: App, period: Duration, activity: &str, run: impl for<'a> Fn(&'a State<App>) -> Run<'a, T>, report: impl Fn(T)) {
+    let app = <&State<App>>::from(&app);
+    let mut interval = time::interval(period);
+
+    loop {
+        interval.tick().await;
+
+        match run(app).await {
+            Ok(outcome) => report(outcome),
+            Err(err) => error!("A unknown error occurred while {} \n {}", activity, err),
+        }
+    }
+}
\ No newline at end of file
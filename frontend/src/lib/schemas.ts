@@ -151,31 +151,23 @@ export const projectsSchema = z.array(
 
 export const serverStatusSchema = z.union([
 	z.object({
-		ip: z.string(),
-		port: z.number(),
-		debug: z.unknown(),
-		motd: z.object({
-			raw: z.array(z.string()),
-			clean: z.array(z.string()),
-			html: z.array(z.string())
-		}),
-		players: z.object({
-			online: z.number(),
-			max: z.number(),
-			list: z.array(z.object({ name: z.string(), uuid: z.string() }))
-		}),
-		version: z.string(),
+		server: z.string(),
+		address: z.string(),
 		online: z.literal(true),
-		protocol: z.object({ version: z.number(), name: z.string() }),
-		hostname: z.string(),
-		map: z.optional(z.object({ raw: z.string(), clean: z.string(), html: z.string() })),
-		eula_blocked: z.boolean()
+		players: z
+			.object({
+				online: z.number(),
+				max: z.number(),
+				sample: z.array(z.object({ name: z.string(), id: z.string() }))
+			})
+			.nullable(),
+		motd: z.string().nullable(),
+		version: z.string().nullable(),
+		latency_ms: z.number().nullable()
 	}),
 	z.object({
-		ip: z.string(),
-		port: z.number(),
-		debug: z.unknown(),
-		hostname: z.string(),
+		server: z.string(),
+		address: z.string(),
 		online: z.literal(false)
 	})
 ]);